@@ -1,5 +1,4 @@
 #![allow(non_snake_case)]
-use futures::executor::block_on;
 use std::process::exit;
 
 use bodo_connect::cmd;
@@ -16,7 +15,7 @@ async fn main() {
     if !cmd.migrate_to_yaml {
         cmd.check_host();
     }
-    exit(match block_on(cmd.main()) {
+    exit(match cmd.main().await {
         Ok(..) => 0,
         Err(e) => {
             e.print_error();
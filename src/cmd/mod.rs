@@ -2,15 +2,14 @@ mod runtime_error;
 #[cfg(feature = "sshfs")]
 pub mod sshfs;
 
-use std::path::PathBuf;
-use std::time::{Duration, Instant};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 pub use runtime_error::RuntimeError;
 
-use crate::config::Config;
+use crate::config::{Config, RouteSelection};
 #[cfg(feature = "log")]
 use crate::logger::CONSOLE_LOGGER;
-use crate::net::ConnectionMethod;
 #[allow(unused_imports)]
 #[cfg(not(feature = "log"))]
 use crate::{error, warn, info, debug};
@@ -19,10 +18,17 @@ use crate::{error, warn, info, debug};
 use log::{error, warn, info, debug, LevelFilter};
 use clap::{Parser,CommandFactory};
 use clap::error::{ContextKind, ContextValue, ErrorKind, RichFormatter};
-use subprocess::ExitStatus;
+use futures::FutureExt;
 
+use crate::net::cache::DetectionCache;
+use crate::net::detector::DetectorConfig;
 use crate::ssh::options::GenericOption;
-use crate::ssh::SSHOptionStore;
+use crate::ssh::{shell, SSHOptionStore};
+#[cfg(feature = "sync")]
+use crate::ssh::process::Stdout;
+use crate::ssh::runner::{Runner, Termination};
+#[cfg(feature = "wake")]
+use crate::waker::WakeError;
 use crate::config::{CONFIG_SEARCH_FILE,CONFIG_SEARCH_FOLDER};
 use std::io::Write;
 
@@ -58,10 +64,24 @@ pub struct Cmd {
     #[cfg(feature = "sshfs")]
     #[arg(short = 'S', long, help = "Creates sshfs commands")]
     sshfs: bool,
+    #[arg(long, help = "Probe every candidate first hop and pick the fastest route")]
+    fastest: bool,
+    #[arg(long, value_delimiter = ',', help = "Comma separated hosts to jump through, replacing the computed route (empty to ignore the configured one)")]
+    via: Option<Vec<String>>,
+    #[arg(long, value_delimiter = ',', help = "Comma separated subnet detectors to run, in order (external_ip, cidr, gateway_mac, master_probe)")]
+    detectors: Option<Vec<DetectorConfig>>,
+    #[arg(long, help = "Don't contact any external service to detect the current subnet")]
+    no_external: bool,
+    #[arg(long, help = "Don't use nor update the subnet detection cache")]
+    no_cache: bool,
+    #[arg(long, help = "Flush the subnet detection cache before connecting")]
+    flush_cache: bool,
     #[arg(short, long, help = "Retry connection until ssh returns 0")]
     loop_: bool,
     #[arg(short = 'e', help = "Specify ssh-like command to execute and eventual options.")]
     cmd: Option<String>,
+    #[arg(short = 'o', long = "option", value_parser = parse_ssh_option, help = "ssh_config option as Key=Value, overriding the configured ones (can be repeated)")]
+    options: Vec<(String, String)>,
     #[arg(long, help = "Migrate from json to yaml format")]
     pub migrate_to_yaml: bool,
     #[cfg(feature = "sync")]
@@ -80,6 +100,13 @@ pub struct Cmd {
     extra: Vec<String>
 }
 
+fn parse_ssh_option(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((k, v)) if !k.trim().is_empty() => Ok((k.trim().to_owned(), v.trim().to_owned())),
+        _ => Err(format!("expected Key=Value, got {}", s)),
+    }
+}
+
 impl Cmd {
     pub fn search_cfg(&self) -> Vec<String> {
         let home_dir = match home::home_dir() {
@@ -112,7 +139,7 @@ impl Cmd {
         }
 
         let default = Config::default_path(Some(home_dir));
-        if results.is_empty() && default != PathBuf::from(&results[0]) {
+        if !results.is_empty() && default != Path::new(&results[0]) {
             warn!(
                 "deprecation warning: configuration is not in the default location ({:?})",
                 default
@@ -121,6 +148,14 @@ impl Cmd {
         results
     }
 
+    /// Default location of the detection cache, in the user cache directory.
+    pub fn default_cache_path() -> Option<PathBuf> {
+        std::env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| home::home_dir().map(|h| h.join(".cache")))
+            .map(|d| d.join("bodo_connect").join("detection.json"))
+    }
+
     pub fn load_cfg(&self) -> Result<Config, RuntimeError> {
         match &self.config {
             Some(f) => self.cfg_from_file(f),
@@ -175,14 +210,47 @@ impl Cmd {
             Err(e) => return Err(e),
         };
 
-        // settings don't exist yet
-        let (nm, _settings) = cfg.split();
+        let (mut nm, _settings) = cfg.split();
+        if self.fastest {
+            nm.settings_mut().route_selection = RouteSelection::Latency;
+        }
+        if let Some(detectors) = &self.detectors {
+            nm.settings_mut().detectors = detectors.clone();
+        }
+        if self.no_external {
+            nm.settings_mut().external_lookups = false;
+        }
+        if nm.settings().cache_path.is_none() {
+            nm.settings_mut().cache_path = Cmd::default_cache_path();
+        }
+        if self.no_cache {
+            nm.settings_mut().cache_ttl = 0;
+        }
+        if self.flush_cache {
+            if let Some(p) = &nm.settings().cache_path {
+                info!("flushing detection cache {:?}", p);
+                DetectionCache::flush(p)?;
+            }
+        }
+
+        if let Some(via) = &self.via {
+            if let Some(h) = nm.get_host_mut(self.host.as_deref().unwrap()) {
+                h.via = via.iter().filter(|v| !v.is_empty()).cloned().collect();
+            }
+        }
 
         if let Some(target) = nm.get_host(&self.host.clone().unwrap()) {
-            let mut extra_options = SSHOptionStore::new(self.cmd.clone());
+            let mut extra_options = SSHOptionStore::new(None);
+            if let Some(c) = &self.cmd {
+                let words = shell::split(c).map_err(|e| RuntimeError::ParseError(format!("invalid command {}: {}", c, e)))?;
+                extra_options.cmd = Some(words);
+            }
 
             if self.tty {
-                extra_options.add_option(Box::new(GenericOption::Switch("t")))
+                extra_options.add_option(Box::new(GenericOption::Switch("t".into())))
+            }
+            for (k, v) in self.options.iter() {
+                extra_options.set_config_option(k.clone(), v.clone());
             }
 
             debug!("extra arguments: {:?}", self.extra);
@@ -207,32 +275,12 @@ impl Cmd {
                 }
             }
 
-            #[cfg(feature = "direct")]
-            let connection = {
-                debug!("check if we can get a direct connection");
-                if let Some(addrs) = &target.addrs {
-                    match addrs.iter().find(|addr| crate::net::NetworkMap::is_available(addr, Some(target.port))) {
-                        Some(addr) => {
-                            debug!("direct connection is available using address {}", addr);
-                            ConnectionMethod::Direct(addr.clone())
-                        }
-                        None => {
-                            debug!("no direct address is available");
-                            ConnectionMethod::ViaSubnet(nm.find_current_subnet().await)
-                        }
-                    }
-                } else {
-                    debug!("direct ips not set");
-                    ConnectionMethod::ViaSubnet(nm.find_current_subnet().await)
-                }
-            };
-            #[cfg(not(feature = "direct"))]
-            let connection = ConnectionMethod::ViaSubnet(nm.find_current_subnet().await);
+            let (connection, probes) = nm.plan_connection(target).await?;
 
             #[cfg(feature = "sshfs")]
-            let mut proc = if self.sshfs {
+            let proc = if self.sshfs {
                 if self.extra.len() == 2 {
-                    nm.to_sshfs(target, connection, self.extra[0].clone(), self.extra[1].clone()).await
+                    nm.to_sshfs(target, connection, self.extra[0].clone(), self.extra[1].clone()).await?
                 } else {
                     return if self.extra.len() < 2 {
                         Err(RuntimeError::TooFewArguments)
@@ -245,26 +293,27 @@ impl Cmd {
                     }
                 }
             } else {
-                nm.to_ssh(target, connection, &self.extra, Some(extra_options)).await
+                nm.to_ssh(target, connection, &self.extra, Some(extra_options)).await?
             };
 
             #[cfg(not(feature = "sshfs"))]
-            let mut proc = nm.to_ssh(target, connection, &mut self.extra, Some(extra_options)).await;
+            let proc = nm.to_ssh(target, connection, &self.extra, Some(extra_options)).await?;
 
             #[cfg(feature = "wake")]
             if self.wake {
-                #[cfg(feature = "log")]
-                if let Err(s) = nm.wake(target).await {
-                    error!("while waking: {}", s);
-                }
-                #[cfg(not(feature = "log"))]
-                if let Err(_) = nm.wake(target).await {
-                    // nothing 'till now
+                match nm.wake(target).await {
+                    Ok(()) => {}
+                    Err(WakeError::Routing(e)) => return Err(e.into()),
+                    #[cfg_attr(not(feature = "log"), allow(unused_variables))]
+                    Err(WakeError::Failed(s)) => error!("while waking: {}", s),
                 }
             }
 
             if self.dry {
                 if !self.quiet {
+                    for p in probes.iter() {
+                        println!("# probe {}", p);
+                    }
                     println!("{}", proc);
                 }
                 Ok(())
@@ -273,35 +322,43 @@ impl Cmd {
                     eprintln!("{}", proc);
                 }
 
-                let mut connection_start;
+                // interactive sessions get Ctrl-C through the tty, otherwise it's forwarded
+                let runner = Runner::new().forward_interrupt(true);
+                let command = proc.command();
+                // listening replaces the default handling of Ctrl-C, so a single listener
+                // covers the whole loop, back-off included
+                let interrupt = tokio::signal::ctrl_c();
+                tokio::pin!(interrupt);
+                let _ = interrupt.as_mut().now_or_never();
                 loop {
-                    connection_start = Instant::now();
-                    if let Some(r) = match proc.run() {
-                        Ok(e) => match e {
-                            ExitStatus::Exited(s) => {
-                                if s == 0 {
-                                    Some(Ok(()))
-                                } else if self.loop_ {
-                                    if s == 255 {
-                                        warn!("ssh exited with {}", s);
-                                        None
-                                    } else {
-                                        Some(Err(RuntimeError::SSHError(s as i32)))
+                    let out = runner.run(&command).await;
+                    // ssh exits on its own when Ctrl-C reaches it through the tty
+                    let interrupted = interrupt.as_mut().now_or_never().is_some();
+                    let r = match out {
+                        Ok(o) if interrupted || o.termination == Termination::Interrupted => Some(Err(RuntimeError::Interrupted)),
+                        Ok(o) => match o.code {
+                            Some(0) => Some(Ok(())),
+                            Some(255) if self.loop_ => {
+                                warn!("ssh exited with 255");
+                                if o.duration < Duration::from_millis(200) {
+                                    tokio::select! {
+                                        biased;
+                                        _ = &mut interrupt => return Err(RuntimeError::Interrupted),
+                                        _ = tokio::time::sleep(Duration::from_secs(1)) => {}
                                     }
-                                } else {
-                                    Some(Err(RuntimeError::SSHError(s as i32)))
                                 }
+                                None
                             }
-                            _ => Some(Err(RuntimeError::SSHUnknownError)),
+                            Some(s) => Some(Err(RuntimeError::SSHError(s))),
+                            None => Some(Err(RuntimeError::SSHUnknownError)),
                         },
                         Err(e) => Some(Err(RuntimeError::SpawnError(
                             proc.to_string(),
                             e.to_string(),
                         ))),
-                    } {
+                    };
+                    if let Some(r) = r {
                         return r;
-                    } else if connection_start.elapsed().as_millis() < 200 {
-                        tokio::time::sleep(Duration::from_secs(1)).await;
                     }
                 }
             }
@@ -363,13 +420,13 @@ impl Cmd {
         } else {
             None
         };
-        let proc = nm.to_ssh_sync(target, None, push.is_some()).await;
+        let proc = nm.to_ssh_sync(target, &[], push.is_some()).await?;
         match push {
             Some(c) => {
-                let captured = proc
-                    .exec()
-                    .stdin(serde_yml::to_string(&c).unwrap().as_str())
-                    .capture();
+                let captured = Runner::new()
+                    .input(serde_yml::to_string(&c)?)
+                    .run(&proc.command())
+                    .await;
 
                 match captured {
                     Ok(e) => {
@@ -386,10 +443,9 @@ impl Cmd {
                 }
             }
             None => {
-                let output = proc
-                    .exec()
-                    .stdout(subprocess::Redirection::Pipe)
-                    .capture()
+                let output = Runner::new()
+                    .run(&proc.command().stdout(Stdout::Pipe))
+                    .await
                     .map_err(|e| RuntimeError::SpawnError(proc.to_string(), e.to_string()))?;
                 let output = if output.success() {
                     output.stdout_str()
@@ -1,6 +1,7 @@
 use std::error::Error;
 use std::fmt::{Debug, Display, Formatter};
 use crate::config::ConfigError;
+use crate::net::RoutingError;
 #[cfg(not(feature = "log"))]
 use crate::error;
 #[cfg(feature = "log")]
@@ -23,12 +24,14 @@ pub enum RuntimeError {
     TooFewArguments,
     SSHError(i32),
     SSHUnknownError,
+    Interrupted,
     SpawnError(String, String),
     NoSuchHost(String),
     MigrationError(Box<RuntimeError>),
     UnknownError(Box<dyn Printable>),
     SerializationError(toml::ser::Error),
     ConfigError(ConfigError),
+    RoutingError(RoutingError),
     #[cfg(feature = "sync")]
     SyncError(String),
     UnknownUnrepresentableError
@@ -45,9 +48,11 @@ impl RuntimeError {
             RuntimeError::DuplicateError(h) => error!("host duplicate: {}", h),
             RuntimeError::SSHError(e) => error!("ssh exited with code {}", e),
             RuntimeError::SSHUnknownError => error!("unknown ssh error"),
+            RuntimeError::Interrupted => error!("interrupted"),
             RuntimeError::SpawnError(s, e) => error!("cannot spawn ssh command `{}`: {}", s, e),
             RuntimeError::NoSuchHost(h) => error!("no such host or alias: {}", h),
             RuntimeError::ConfigError(e) => error!("configuration error: {}", e),
+            RuntimeError::RoutingError(e) => error!("routing error: {}", e),
             RuntimeError::UnknownError(b) => error!("unkwnown error: {}", b.to_string()),
             RuntimeError::UnknownUnrepresentableError => error!("unkwnown error"),
             #[cfg(feature = "sshfs")]
@@ -81,6 +86,7 @@ impl RuntimeError {
             RuntimeError::SSHError(e) => *e,
             RuntimeError::NoSuchHost(..) => 7,
             RuntimeError::SSHUnknownError => -1,
+            RuntimeError::Interrupted => 130,
             RuntimeError::SpawnError(..) => -2,
             RuntimeError::UnknownError(..) => -3,
             RuntimeError::UnknownUnrepresentableError => -4,
@@ -93,6 +99,15 @@ impl RuntimeError {
             #[cfg(feature = "sync")]
             RuntimeError::SyncError(..) => 12,
             RuntimeError::ConfigError(..) => 13,
+            RuntimeError::RoutingError(e) => match e {
+                RoutingError::NoMaster(..) => 20,
+                RoutingError::NotAMaster(..) => 21,
+                RoutingError::NotInSubnet(..) => 22,
+                RoutingError::UnknownGateway(..) => 23,
+                RoutingError::RoutingCycle(..) => 24,
+                RoutingError::UnknownVia(..) => 25,
+                RoutingError::UnknownRelay(..) => 26,
+            },
         }
     }
 }
@@ -154,6 +169,12 @@ impl From<serde_yml::Error> for RuntimeError {
     }
 }
 
+impl From<RoutingError> for RuntimeError {
+    fn from(value: RoutingError) -> Self {
+        RuntimeError::RoutingError(value)
+    }
+}
+
 impl From<ConfigError> for RuntimeError {
     fn from(value: ConfigError) -> Self {
         RuntimeError::ConfigError(value)
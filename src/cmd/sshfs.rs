@@ -1,6 +1,6 @@
 use std::collections::BTreeMap;
 use crate::ssh::SSHOptionStore;
-use crate::ssh::process::Process;
+use crate::ssh::process::{Command, Process};
 
 pub struct SSHFSProcess {
     ssh_options: SSHOptionStore,
@@ -21,13 +21,18 @@ impl SSHFSProcess {
 }
 
 impl Process for SSHFSProcess {
-    fn get_args(&self) -> Vec<String> {
-        let mut args = vec!["sudo".to_string(), "--preserve-env=SSH_AUTH_SOCK".to_string(), "sshfs".to_string()];
+    fn command(&self) -> Command {
+        let mut args = vec!["--preserve-env=SSH_AUTH_SOCK".to_string(), "sshfs".to_string()];
 
         let mut options: BTreeMap<String, Option<String>> = BTreeMap::from([("allow_other".to_string(), None)]);
         let ssh_options = self.ssh_options.args_gen();
         if !ssh_options.is_empty() {
-            options.insert("ssh_command".to_string(), Some(format!("'ssh {}'", ssh_options.join(" "))));
+            // sshfs splits the command at spaces, honouring backslash escapes
+            let command = std::iter::once("ssh".to_owned())
+                .chain(ssh_options.iter().map(|a| a.replace('\\', "\\\\").replace(' ', "\\ ")))
+                .collect::<Vec<String>>()
+                .join(" ");
+            options.insert("ssh_command".to_string(), Some(command));
         }
 
         if !options.is_empty() {
@@ -36,7 +41,8 @@ impl Process for SSHFSProcess {
                 .iter()
                 .map(|(k, val)| {
                     match val {
-                        Some(v) => format!("{}={}", k, v),
+                        // backslashes and commas are escaped once more for the -o parser
+                        Some(v) => format!("{}={}", k, v.replace('\\', "\\\\").replace(',', "\\,")),
                         None => k.to_string()
                     }
                 })
@@ -47,6 +53,6 @@ impl Process for SSHFSProcess {
 
         args.push(format!("{}:{}", self.identity, self.remote));
         args.push(self.mountpoint.clone());
-        args
+        Command::new("sudo").args(args)
     }
 }
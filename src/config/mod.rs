@@ -5,6 +5,9 @@ use {
     std::fs::read_to_string,
 };
 use crate::net::{NetworkMap, NetworkMapError};
+use crate::net::detector::DetectorConfig;
+use crate::net::external_ip::{ConnectivityCheck, IpProvider};
+use crate::net::probe::PROBE_TIMEOUT;
 use std::{path::PathBuf, fmt::Display};
 
 #[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
@@ -36,7 +39,8 @@ impl From<NetworkMapError> for ConfigError {
 }
 
 impl Config {
-    pub fn split(self) -> (NetworkMap, Settings) {
+    pub fn split(mut self) -> (NetworkMap, Settings) {
+        *self.networkmap.settings_mut() = self.settings.clone();
         (self.networkmap, self.settings)
     }
 
@@ -55,8 +59,94 @@ impl Config {
 }
 
 #[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
-#[derive(Debug,Default)]
+#[derive(Debug,Clone)]
 pub struct Settings {
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub route_selection: RouteSelection,
+    // strategies used to detect the current subnet, in order
+    #[cfg_attr(feature = "serde", serde(default = "DetectorConfig::default_chain"))]
+    pub detectors: Vec<DetectorConfig>,
+    // minimum confidence for a detection to be trusted
+    #[cfg_attr(feature = "serde", serde(default = "default_detection_threshold"))]
+    pub detection_threshold: u8,
+    // timeout of reachability probes, in milliseconds
+    #[cfg_attr(feature = "serde", serde(default = "default_probe_timeout"))]
+    pub probe_timeout: u64,
+    // timeout of the resolution of the subnets subdomains, in milliseconds
+    #[cfg_attr(feature = "serde", serde(default = "default_resolve_timeout"))]
+    pub resolve_timeout: u64,
+    // head start of the direct addresses over the routed path, in milliseconds
+    #[cfg_attr(feature = "serde", serde(default = "default_race_head_start"))]
+    pub race_head_start: u64,
+    // when false, no external service is contacted to detect the current subnet
+    #[cfg_attr(feature = "serde", serde(default = "default_external_lookups"))]
+    pub external_lookups: bool,
+    // ip-echo endpoints used to get the external addresses, in order
+    #[cfg_attr(feature = "serde", serde(default = "IpProvider::default_providers"))]
+    pub ip_providers: Vec<IpProvider>,
+    // endpoints used to check whether the client is online
+    #[cfg_attr(feature = "serde", serde(default = "ConnectivityCheck::default_checks"))]
+    pub connectivity_checks: Vec<ConnectivityCheck>,
+    // where the subnet detection results are cached, caching is disabled when not set
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub cache_path: Option<PathBuf>,
+    // validity of the cached detection results, in seconds
+    #[cfg_attr(feature = "serde", serde(default = "default_cache_ttl"))]
+    pub cache_ttl: u64,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Settings {
+            route_selection: RouteSelection::default(),
+            detectors: DetectorConfig::default_chain(),
+            detection_threshold: default_detection_threshold(),
+            probe_timeout: default_probe_timeout(),
+            resolve_timeout: default_resolve_timeout(),
+            race_head_start: default_race_head_start(),
+            external_lookups: default_external_lookups(),
+            ip_providers: IpProvider::default_providers(),
+            connectivity_checks: ConnectivityCheck::default_checks(),
+            cache_path: None,
+            cache_ttl: default_cache_ttl(),
+        }
+    }
+}
+
+const fn default_detection_threshold() -> u8 {
+    50
+}
+
+const fn default_probe_timeout() -> u64 {
+    PROBE_TIMEOUT.as_millis() as u64
+}
+
+const fn default_resolve_timeout() -> u64 {
+    1000
+}
+
+const fn default_race_head_start() -> u64 {
+    250
+}
+
+const fn default_external_lookups() -> bool {
+    true
+}
+
+const fn default_cache_ttl() -> u64 {
+    600
+}
+
+/// How the router chooses between the available routes to a host.
+#[cfg_attr(feature = "serde", derive(Serialize,Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[derive(Debug,Default,Clone,Copy,PartialEq)]
+pub enum RouteSelection {
+    /// Follow the fixed rules: direct addresses first, then masters by priority.
+    #[default]
+    Static,
+    /// Probe every candidate first hop and pick the fastest one.
+    Latency,
 }
 
 #[cfg(feature = "serde")]
@@ -0,0 +1,139 @@
+//! On-disk cache of the subnet detection results.
+//!
+//! Results are keyed by the [fingerprint](crate::net::local::LocalNetwork::fingerprint) of the local network, so
+//! that they are reused only as long as the client doesn't move to another network (the
+//! detected subnets also by the detection settings), and they expire after `cache_ttl` seconds
+//! (see [`Settings`](crate::config::Settings)).
+#![cfg_attr(not(feature = "serde"), allow(unused_variables))]
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io;
+use std::net::IpAddr;
+use std::path::Path;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default)]
+pub struct DetectionCache {
+    entries: HashMap<String, CacheEntry>,
+    // addresses of the subnet subdomains, they don't depend on the local network
+    #[cfg_attr(feature = "serde", serde(default))]
+    resolved: HashMap<String, Cached<Vec<IpAddr>>>,
+}
+
+/// Results cached for a single local network.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Default)]
+pub struct CacheEntry {
+    // subdomains of the detected subnets
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub subnets: Option<Cached<Vec<String>>>,
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub external_ips: Option<Cached<Vec<IpAddr>>>,
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug)]
+pub struct Cached<T> {
+    pub value: T,
+    // unix timestamp, in seconds
+    pub timestamp: u64,
+}
+
+/// FNV-1a digest of `data`. Unlike the std hashers, it doesn't change across Rust releases, so
+/// the keys of the cache stay valid on disk.
+pub fn digest(data: &str) -> String {
+    let hash = data
+        .bytes()
+        .fold(0xcbf2_9ce4_8422_2325_u64, |h, b| (h ^ b as u64).wrapping_mul(0x0100_0000_01b3));
+    format!("{:016x}", hash)
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+impl<T> Cached<T> {
+    pub fn new(value: T) -> Cached<T> {
+        Cached { value, timestamp: now() }
+    }
+
+    pub fn is_fresh(&self, ttl: u64) -> bool {
+        now().saturating_sub(self.timestamp) < ttl
+    }
+}
+
+impl DetectionCache {
+    /// Loads the cache from `path`. A missing or unreadable cache is just empty.
+    pub fn load(path: &Path) -> DetectionCache {
+        #[cfg(feature = "serde")]
+        if let Ok(content) = std::fs::read_to_string(path) {
+            return serde_json::from_str(&content).unwrap_or_default();
+        }
+        DetectionCache::default()
+    }
+
+    /// Saves the cache to `path`, dropping the entries older than `ttl` seconds.
+    pub fn save(&mut self, path: &Path, ttl: u64) -> io::Result<()> {
+        self.entries.retain(|_, e| {
+            e.subnets.as_ref().is_some_and(|c| c.is_fresh(ttl))
+                || e.external_ips.as_ref().is_some_and(|c| c.is_fresh(ttl))
+        });
+        self.resolved.retain(|_, c| c.is_fresh(ttl));
+        #[cfg(feature = "serde")]
+        {
+            if let Some(p) = path.parent() {
+                std::fs::create_dir_all(p)?;
+            }
+            std::fs::write(path, serde_json::to_string(self)?)?;
+        }
+        Ok(())
+    }
+
+    /// Deletes the cache at `path`, if any.
+    pub fn flush(path: &Path) -> io::Result<()> {
+        match std::fs::remove_file(path) {
+            Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+            _ => Ok(()),
+        }
+    }
+
+    pub fn get_subnets(&self, key: &str, ttl: u64) -> Option<&[String]> {
+        self.entries
+            .get(key)
+            .and_then(|e| e.subnets.as_ref())
+            .filter(|c| c.is_fresh(ttl))
+            .map(|c| c.value.as_slice())
+    }
+
+    pub fn set_subnets(&mut self, key: &str, subnets: Vec<String>) {
+        self.entries.entry(key.to_owned()).or_default().subnets = Some(Cached::new(subnets));
+    }
+
+    pub fn get_external_ips(&self, key: &str, ttl: u64) -> Option<&[IpAddr]> {
+        self.entries
+            .get(key)
+            .and_then(|e| e.external_ips.as_ref())
+            .filter(|c| c.is_fresh(ttl))
+            .map(|c| c.value.as_slice())
+    }
+
+    pub fn set_external_ips(&mut self, key: &str, ips: Vec<IpAddr>) {
+        self.entries.entry(key.to_owned()).or_default().external_ips = Some(Cached::new(ips));
+    }
+
+    pub fn get_resolved(&self, subdomain: &str, ttl: u64) -> Option<&[IpAddr]> {
+        self.resolved
+            .get(subdomain)
+            .filter(|c| c.is_fresh(ttl))
+            .map(|c| c.value.as_slice())
+    }
+
+    pub fn set_resolved(&mut self, subdomain: &str, addrs: Vec<IpAddr>) {
+        self.resolved.insert(subdomain.to_owned(), Cached::new(addrs));
+    }
+}
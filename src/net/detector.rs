@@ -0,0 +1,350 @@
+//! Strategies used to detect the subnet in which the client currently is.
+//!
+//! Each [`SubnetDetector`] returns the subnets it recognizes, along with a confidence score
+//! between 0 and 100. Detectors are run in the order configured in
+//! [`Settings::detectors`](crate::config::Settings), see [`NetworkMap::detect_subnets`].
+#![cfg_attr(not(feature = "log"), allow(unused_variables))]
+#[cfg(not(feature = "log"))]
+use crate::{debug, info, warn};
+#[cfg(feature = "log")]
+use log::{debug, info, warn};
+use futures::future::{join_all, BoxFuture};
+use futures::FutureExt;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
+use std::fmt::Display;
+use std::str::FromStr;
+use std::time::Duration;
+
+use crate::net::cache::DetectionCache;
+use crate::net::external_ip::{get_ips, is_online};
+use crate::net::local::{self, LocalNetwork};
+use crate::net::{NetworkMap, Probe, Subnet};
+use crate::ssh::keyscan::host_key_fingerprints;
+use crate::ssh::process::Command;
+use crate::ssh::runner::Runner;
+
+/// A subnet recognized by a detector.
+#[derive(Debug)]
+pub struct Detection<'a> {
+    pub subnet: &'a Subnet,
+    // from 0 (pure guess) to 100 (certain)
+    pub confidence: u8,
+}
+
+pub trait SubnetDetector: Send + Sync {
+    fn name(&self) -> String;
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>>;
+}
+
+/// Detector configuration, as found in the settings.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+#[derive(Debug, Clone, PartialEq)]
+pub enum DetectorConfig {
+    /// Matches the external ips (both IPv4 and IPv6) against the `eip` (or resolved `subdomain`)
+    /// and the `eip6` prefix of each subnet.
+    ExternalIp,
+    /// Matches the local interface addresses against the `cidrs` of each subnet.
+    Cidr,
+    /// Matches the mac of the default gateway against the `gateway_mac` of each subnet.
+    GatewayMac,
+    /// Tries to connect to the private address of each master.
+    MasterProbe,
+    /// The client is in `subnet` whenever the network interface `interface` exists.
+    Interface { interface: String, subnet: String },
+    /// Runs `command` with `sh -c`: on success the client is in `subnet`, or in the subnet
+    /// printed by the command when `subnet` is not set. The command is stopped after `timeout`
+    /// milliseconds, the probe timeout by default.
+    Command {
+        command: String,
+        #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+        subnet: Option<String>,
+        #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+        timeout: Option<u64>,
+    },
+}
+
+impl DetectorConfig {
+    pub fn default_chain() -> Vec<DetectorConfig> {
+        vec![DetectorConfig::Cidr, DetectorConfig::ExternalIp, DetectorConfig::MasterProbe]
+    }
+
+    pub fn build(&self) -> Box<dyn SubnetDetector> {
+        match self {
+            DetectorConfig::ExternalIp => Box::new(ExternalIpDetector),
+            DetectorConfig::Cidr => Box::new(CidrDetector),
+            DetectorConfig::GatewayMac => Box::new(GatewayMacDetector),
+            DetectorConfig::MasterProbe => Box::new(MasterProbeDetector),
+            DetectorConfig::Interface { interface, subnet } => Box::new(InterfaceDetector {
+                interface: interface.clone(),
+                subnet: subnet.clone(),
+            }),
+            DetectorConfig::Command { command, subnet, timeout } => Box::new(CommandDetector {
+                command: command.clone(),
+                subnet: subnet.clone(),
+                timeout: timeout.map(Duration::from_millis),
+            }),
+        }
+    }
+}
+
+/// Parses the detectors that don't need any parameter, e.g. from the command line.
+impl FromStr for DetectorConfig {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "external_ip" => Ok(DetectorConfig::ExternalIp),
+            "cidr" => Ok(DetectorConfig::Cidr),
+            "gateway_mac" => Ok(DetectorConfig::GatewayMac),
+            "master_probe" => Ok(DetectorConfig::MasterProbe),
+            "interface" | "command" => Err(format!("detector {} can only be set in the configuration", s)),
+            _ => Err(format!("unknown detector: {}", s)),
+        }
+    }
+}
+
+impl Display for DetectorConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            DetectorConfig::ExternalIp => write!(f, "external_ip"),
+            DetectorConfig::Cidr => write!(f, "cidr"),
+            DetectorConfig::GatewayMac => write!(f, "gateway_mac"),
+            DetectorConfig::MasterProbe => write!(f, "master_probe"),
+            DetectorConfig::Interface { interface, .. } => write!(f, "interface({})", interface),
+            DetectorConfig::Command { command, .. } => write!(f, "command({})", command),
+        }
+    }
+}
+
+pub struct ExternalIpDetector;
+
+impl SubnetDetector for ExternalIpDetector {
+    fn name(&self) -> String {
+        DetectorConfig::ExternalIp.to_string()
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            let settings = nm.settings();
+            if !settings.external_lookups {
+                debug!("external lookups are disabled");
+                return vec![];
+            }
+            let cache = nm.cache_key();
+            let cached = cache
+                .as_ref()
+                .and_then(|(path, key)| DetectionCache::load(path).get_external_ips(key, settings.cache_ttl).map(<[_]>::to_vec));
+            let eips = match cached {
+                Some(eips) => {
+                    debug!("using cached external ips");
+                    eips
+                }
+                None => {
+                    // are we online?
+                    if !is_online(&settings.connectivity_checks).await {
+                        info!("no internet connection detected");
+                        return vec![];
+                    }
+                    debug!("network: we are online");
+                    debug!("getting external ips");
+                    let eips = get_ips(&settings.ip_providers).await;
+                    if eips.is_empty() {
+                        warn!("cannot get external ip");
+                    } else if let Some((path, key)) = &cache {
+                        nm.update_cache(path, |c| c.set_external_ips(key, eips.clone()));
+                    }
+                    eips
+                }
+            };
+            if eips.is_empty() {
+                return vec![];
+            }
+            let resolved = nm.resolve_subdomains().await;
+            let mut detections: Vec<Detection> = vec![];
+            for client_eip in eips {
+                info!("external ip is {}", client_eip);
+                if let Some(subnet) = nm.match_external_ip(client_eip, &resolved) {
+                    if !detections.iter().any(|d| d.subnet == subnet) {
+                        detections.push(Detection { subnet, confidence: 90 });
+                    }
+                }
+            }
+            detections
+        }.boxed()
+    }
+}
+
+pub struct CidrDetector;
+
+impl SubnetDetector for CidrDetector {
+    fn name(&self) -> String {
+        DetectorConfig::Cidr.to_string()
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            let local = LocalNetwork::read();
+            debug!("local network: {:?}", local);
+            nm.get_subnets()
+                .into_iter()
+                .filter(|s| s.matches_local(&local))
+                .map(|subnet| Detection {
+                    subnet,
+                    // a matching gateway makes the match more specific
+                    confidence: 60 + 15 * subnet.gateway.is_some() as u8 + 15 * subnet.gateway_mac.is_some() as u8,
+                })
+                .collect()
+        }.boxed()
+    }
+}
+
+pub struct GatewayMacDetector;
+
+impl SubnetDetector for GatewayMacDetector {
+    fn name(&self) -> String {
+        DetectorConfig::GatewayMac.to_string()
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            let local = LocalNetwork::read();
+            nm.get_subnets()
+                .into_iter()
+                .filter(|s| s.gateway_mac.is_some() && s.matches_gateway_mac(&local))
+                .map(|subnet| Detection { subnet, confidence: 80 })
+                .collect()
+        }.boxed()
+    }
+}
+
+pub struct MasterProbeDetector;
+
+impl SubnetDetector for MasterProbeDetector {
+    fn name(&self) -> String {
+        DetectorConfig::MasterProbe.to_string()
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            debug!("detecting subnet using masters...");
+            let settings = nm.settings();
+            // None when connectivity is unknown, since no external service may be contacted
+            let online = match settings.external_lookups {
+                true => Some(is_online(&settings.connectivity_checks).await),
+                false => None,
+            };
+            let local = LocalNetwork::read();
+            let masters = nm.get_masters();
+            let ips: Vec<String> = masters.iter().map(|(_, m)| m.ip.to_string()).collect();
+            let probes = join_all(
+                masters.iter().zip(ips.iter()).map(|((_, m), ip)| Probe::tcp(ip, m.port, nm.probe_timeout()))
+            ).await;
+            let mut detections: Vec<Detection> = vec![];
+            for ((s, m), probe) in masters.into_iter().zip(probes) {
+                // several subnets can be reachable at once, but one master per subnet is enough
+                if detections.iter().any(|d| d.subnet == s) {
+                    continue;
+                }
+                if probe.latency.is_none() {
+                    debug!("master {} of subnet {} is unavailable", m.name, s.subdomain);
+                    continue;
+                }
+                if !s.matches_gateway_mac(&local) {
+                    debug!("master {} of subnet {} is available, but the gateway mac doesn't match", m.name, s.subdomain);
+                    continue;
+                }
+                if m.host_key.is_some() {
+                    if !m.matches_host_key(&host_key_fingerprints(&m.ip.to_string(), m.port, nm.probe_timeout()).await) {
+                        warn!("master {} of subnet {} is available, but its host key doesn't match", m.name, s.subdomain);
+                        continue;
+                    }
+                    detections.push(Detection { subnet: s, confidence: 95 });
+                    continue;
+                }
+                // an open port alone is weak evidence, unless the gateway is pinned too; once
+                // known to be online, any network could have that port open
+                let confidence = match (s.gateway_mac.is_some(), online) {
+                    (true, _) => 70,
+                    (false, Some(true)) => 40,
+                    (false, _) => 50,
+                };
+                detections.push(Detection { subnet: s, confidence });
+            }
+            detections
+        }.boxed()
+    }
+}
+
+pub struct InterfaceDetector {
+    pub interface: String,
+    pub subnet: String,
+}
+
+impl SubnetDetector for InterfaceDetector {
+    fn name(&self) -> String {
+        format!("interface({})", self.interface)
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            if !local::interface_names().contains(&self.interface) {
+                debug!("interface {} is not present", self.interface);
+                return vec![];
+            }
+            match nm.get_subnet(&self.subnet) {
+                Some(subnet) => vec![Detection { subnet, confidence: 80 }],
+                None => {
+                    warn!("detector {}: unknown subnet {}", self.name(), self.subnet);
+                    vec![]
+                }
+            }
+        }.boxed()
+    }
+}
+
+pub struct CommandDetector {
+    pub command: String,
+    pub subnet: Option<String>,
+    // the probe timeout when not set
+    pub timeout: Option<Duration>,
+}
+
+impl SubnetDetector for CommandDetector {
+    fn name(&self) -> String {
+        format!("command({})", self.command)
+    }
+
+    fn detect<'s, 'a: 's>(&'s self, nm: &'a NetworkMap) -> BoxFuture<'s, Vec<Detection<'a>>> {
+        async move {
+            let command = Command::new("sh").args(["-c", &self.command]);
+            let captured = Runner::new()
+                .capture(true)
+                .timeout(self.timeout.unwrap_or(nm.probe_timeout()))
+                .grace(Duration::from_millis(100))
+                .run(&command)
+                .await;
+            let output = match captured {
+                Ok(c) if c.success() => c.stdout_str(),
+                Ok(c) => {
+                    debug!("detector {} ended with {:?} (code {:?})", self.name(), c.termination, c.code);
+                    return vec![];
+                }
+                Err(e) => {
+                    warn!("cannot run detector {}: {}", self.name(), e);
+                    return vec![];
+                }
+            };
+            let name = self.subnet.clone().unwrap_or(output.trim().to_owned());
+            match nm.get_subnet(&name) {
+                Some(subnet) => vec![Detection { subnet, confidence: 100 }],
+                None => {
+                    warn!("detector {}: unknown subnet {}", self.name(), name);
+                    vec![]
+                }
+            }
+        }.boxed()
+    }
+}
@@ -1,26 +1,227 @@
+//! Lookup of the client external addresses through IP-echo services, and connectivity checks.
+//!
+//! Both are configured in the [`Settings`](crate::config::Settings), and can be disabled
+//! altogether with `external_lookups`.
 #![cfg_attr(not(feature = "log"), allow(unused_variables))]
 use std::net::IpAddr;
 use std::str::FromStr;
 use std::time::Duration;
+use futures::future::join3;
+use futures::stream::{FuturesUnordered, StreamExt};
 #[cfg(feature = "log")]
 use log::debug;
 #[cfg(not(feature = "log"))]
 use crate::debug;
+#[cfg(feature = "serde")]
+use serde::{Deserialize, Serialize};
 
-pub async fn get_ip() -> Option<IpAddr> {
-    let client = reqwest::Client::builder()
-        .timeout(Duration::from_secs(1))
-        .build()
-        .unwrap();
-    match client.get("https://icanhazip.com/").send().await {
-        Ok(res) => match res.text().await {
-            Ok(s) => match IpAddr::from_str(s.trim()) {
-                Ok(i) => return Some(i),
-                Err(e) => debug!("{:?} while parsing {}", e, s)
+use crate::net::probe::PROBE_TIMEOUT;
+use crate::net::Probe;
+
+/// An IP-echo endpoint, answering with the address the request comes from.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct IpProvider {
+    pub url: String,
+    #[cfg_attr(feature = "serde", serde(default))]
+    pub parser: ResponseParser,
+    // address family answered by the endpoint, if known: it is asked along with the other
+    // providers of that family
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub family: Option<IpFamily>,
+    // in milliseconds
+    #[cfg_attr(feature = "serde", serde(default = "default_provider_timeout"))]
+    pub timeout: u64,
+}
+
+/// How the address is extracted from the response of an [`IpProvider`].
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum ResponseParser {
+    /// The whole body is the address.
+    #[default]
+    Plain,
+    /// The body is a json object, the address is the string in `field`.
+    Json { field: String },
+    /// The body is made of `key=value` lines, the address is the value of `key`.
+    KeyValue { key: String },
+}
+
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[cfg_attr(feature = "serde", serde(rename_all = "lowercase"))]
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum IpFamily {
+    Ipv4,
+    Ipv6,
+}
+
+/// A tcp endpoint that is reachable only when the client is online.
+#[cfg_attr(feature = "serde", derive(Serialize, Deserialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConnectivityCheck {
+    pub host: String,
+    #[cfg_attr(feature = "serde", serde(default = "default_check_port"))]
+    pub port: u16,
+    // in milliseconds
+    #[cfg_attr(feature = "serde", serde(default = "default_check_timeout"))]
+    pub timeout: u64,
+}
+
+const fn default_provider_timeout() -> u64 {
+    1000
+}
+
+const fn default_check_port() -> u16 {
+    80
+}
+
+const fn default_check_timeout() -> u64 {
+    PROBE_TIMEOUT.as_millis() as u64
+}
+
+impl IpProvider {
+    pub fn new(url: &str, family: Option<IpFamily>) -> IpProvider {
+        IpProvider {
+            url: url.to_owned(),
+            parser: ResponseParser::Plain,
+            family,
+            timeout: default_provider_timeout(),
+        }
+    }
+
+    pub fn default_providers() -> Vec<IpProvider> {
+        vec![
+            IpProvider::new("https://ipv4.icanhazip.com/", Some(IpFamily::Ipv4)),
+            IpProvider::new("https://ipv6.icanhazip.com/", Some(IpFamily::Ipv6)),
+        ]
+    }
+
+    pub async fn get_ip(&self) -> Option<IpAddr> {
+        let client = reqwest::Client::builder()
+            .timeout(Duration::from_millis(self.timeout))
+            .build()
+            .unwrap();
+        let body = match client.get(&self.url).send().await {
+            Ok(res) => res.text().await,
+            Err(e) => Err(e),
+        };
+        match body {
+            Ok(s) => {
+                let ip = self.parser.parse(&s);
+                if ip.is_none() {
+                    debug!("cannot parse the response of {}: {}", self.url, s);
+                }
+                ip
+            }
+            Err(e) => {
+                debug!("{:?}", e);
+                None
             }
-            Err(e) => debug!("{:?}", e)
-        },
-        Err(e) => debug!("{:?}", e)
+        }
+    }
+}
+
+impl ResponseParser {
+    pub fn parse(&self, body: &str) -> Option<IpAddr> {
+        match self {
+            ResponseParser::Plain => IpAddr::from_str(body.trim()).ok(),
+            #[cfg(feature = "serde")]
+            ResponseParser::Json { field } => serde_json::from_str::<serde_json::Value>(body)
+                .ok()?
+                .get(field)?
+                .as_str()?
+                .parse()
+                .ok(),
+            #[cfg(not(feature = "serde"))]
+            ResponseParser::Json { .. } => None,
+            ResponseParser::KeyValue { key } => body
+                .lines()
+                .filter_map(|l| l.split_once('='))
+                .find(|(k, _)| k.trim() == key)
+                .and_then(|(_, v)| v.trim().parse().ok()),
+        }
+    }
+}
+
+impl IpFamily {
+    pub fn of(ip: &IpAddr) -> IpFamily {
+        match ip {
+            IpAddr::V4(_) => IpFamily::Ipv4,
+            IpAddr::V6(_) => IpFamily::Ipv6,
+        }
+    }
+}
+
+impl ConnectivityCheck {
+    pub fn new(host: &str) -> ConnectivityCheck {
+        ConnectivityCheck {
+            host: host.to_owned(),
+            port: default_check_port(),
+            timeout: default_check_timeout(),
+        }
+    }
+
+    pub fn default_checks() -> Vec<ConnectivityCheck> {
+        vec![
+            ConnectivityCheck::new("1.1.1.1"),
+            ConnectivityCheck::new("2606:4700:4700::1111"),
+        ]
+    }
+}
+
+/// Gets the external addresses of the client, at most one per address family, IPv4 first.
+/// The providers of each family are asked in order, the IPv4 and IPv6 ones concurrently; those
+/// of unknown family are asked in order alongside them, for the families the others don't find.
+pub async fn get_ips(providers: &[IpProvider]) -> Vec<IpAddr> {
+    let of = |family: Option<IpFamily>| providers.iter().filter(|p| p.family == family).collect::<Vec<_>>();
+    let (v4, v6, any) = join3(
+        ask_in_order(&of(Some(IpFamily::Ipv4)), &[IpFamily::Ipv4]),
+        ask_in_order(&of(Some(IpFamily::Ipv6)), &[IpFamily::Ipv6]),
+        ask_in_order(&of(None), &[IpFamily::Ipv4, IpFamily::Ipv6]),
+    ).await;
+    let mut ips: Vec<IpAddr> = v4.into_iter().chain(v6).collect();
+    for ip in any {
+        if !ips.iter().any(|i| IpFamily::of(i) == IpFamily::of(&ip)) {
+            ips.push(ip)
+        }
+    }
+    ips.sort_by_key(|ip| ip.is_ipv6());
+    ips
+}
+
+/// Asks the `providers` in order until an address of every family in `wanted` is known.
+async fn ask_in_order(providers: &[&IpProvider], wanted: &[IpFamily]) -> Vec<IpAddr> {
+    let mut ips: Vec<IpAddr> = vec![];
+    for p in providers {
+        if wanted.iter().all(|f| ips.iter().any(|ip| IpFamily::of(ip) == *f)) {
+            break;
+        }
+        debug!("getting external ip from {}", p.url);
+        match p.get_ip().await {
+            Some(ip) if !wanted.contains(&IpFamily::of(&ip)) => debug!("external ip {} from {} ignored, it's not of the expected family", ip, p.url),
+            Some(ip) if ips.iter().any(|i| IpFamily::of(i) == IpFamily::of(&ip)) => debug!("external ip {} from {} ignored, its family is already known", ip, p.url),
+            Some(ip) => ips.push(ip),
+            None => debug!("cannot get external ip from {}", p.url),
+        }
+    }
+    ips
+}
+
+/// Runs the connectivity `checks` concurrently, returning as soon as one of them succeeds.
+/// Without any check the client is assumed to be online.
+pub async fn is_online(checks: &[ConnectivityCheck]) -> bool {
+    if checks.is_empty() {
+        return true;
+    }
+    let mut pending: FuturesUnordered<_> = checks
+        .iter()
+        .map(|c| Probe::tcp(&c.host, c.port, Duration::from_millis(c.timeout)))
+        .collect();
+    while let Some(probe) = pending.next().await {
+        if probe.latency.is_some() {
+            return true;
+        }
     }
-    None
-}
\ No newline at end of file
+    false
+}
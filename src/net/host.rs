@@ -2,11 +2,13 @@
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize,Serialize};
-use std::collections::HashSet;
-use std::net::IpAddr;
+use std::collections::{BTreeMap, HashSet};
+use std::net::{IpAddr, Ipv6Addr};
 
-use crate::net::Subnet;
+use crate::net::{Endpoint, RoutingError, Subnet};
+use crate::net::local::is_global_ipv6;
 use crate::ssh::hop::Hop;
+use crate::ssh::keyscan::same_fingerprint;
 use crate::ssh::options::PortOption;
 #[cfg(feature = "wake")]
 use crate::waker::Waker;
@@ -19,6 +21,9 @@ pub struct Host {
     #[cfg_attr(feature = "serde", serde(default))]
     pub aliases: HashSet<String>,
     pub ip: IpAddr,
+    // global IPv6 address, reachable directly from anywhere with IPv6 connectivity
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub ipv6: Option<Ipv6Addr>,
     #[cfg_attr(feature = "serde", serde(default = "crate::ssh::default_port"))]
     pub port: u16,
     // if this is not None then the host is a network master
@@ -30,6 +35,27 @@ pub struct Host {
         ),
     )]
     pub eport: Option<u16>,
+    // preference among the masters of the same subnet, lower values are tried first
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub priority: Option<u16>,
+    // pinned fingerprint of the ssh host key, e.g. `SHA256:...`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub host_key: Option<String>,
+    // names of the hosts to jump through, in order, replacing the computed route
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub via: Vec<String>,
+    // reverse tunnel through which the host is reached from outside its subnet
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub relay: Option<Relay>,
+    // proxy in front of the external address of a master
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub proxy: Option<Proxy>,
+    // ssh_config options (e.g. `IdentityFile`), overriding those of the subnet
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "BTreeMap::is_empty", deserialize_with = "deserialize_ssh_options"),
+    )]
+    pub ssh_options: BTreeMap<String, String>,
     pub user: String,
     #[cfg(feature = "wake")]
     #[cfg_attr(feature = "serde", serde(skip_serializing_if = "Option::is_none"))]
@@ -42,7 +68,81 @@ pub struct Host {
     pub addrs: Option<Vec<String>>,
 }
 
+/// Deserializes ssh_config options, accepting numbers and booleans (as `yes`/`no`) as values.
+#[cfg(feature = "serde")]
+pub(crate) fn deserialize_ssh_options<'de, D>(deserializer: D) -> Result<BTreeMap<String, String>, D::Error>
+where
+    D: serde::Deserializer<'de>,
+{
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum Value {
+        Bool(bool),
+        Int(i64),
+        Float(f64),
+        String(String),
+    }
+
+    Ok(BTreeMap::<String, Value>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(k, v)| {
+            let v = match v {
+                Value::Bool(b) => if b { "yes" } else { "no" }.to_owned(),
+                Value::Int(i) => i.to_string(),
+                Value::Float(f) => f.to_string(),
+                Value::String(s) => s,
+            };
+            (k, v)
+        })
+        .collect())
+}
+
+/// A reverse tunnel kept open by a host without inbound connectivity (e.g. with `ssh -R`), so
+/// that it can be reached at `bind:port` from the relay host.
+#[cfg_attr(feature = "serde", derive(Deserialize,Serialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Relay {
+    // name of the relay host, in the network map
+    pub host: String,
+    pub port: u16,
+    #[cfg_attr(feature = "serde", serde(default = "default_relay_bind"))]
+    pub bind: String,
+}
+
+fn default_relay_bind() -> String {
+    "localhost".to_owned()
+}
+
+impl Relay {
+    pub fn new(host: String, port: u16) -> Relay {
+        Relay { host, port, bind: default_relay_bind() }
+    }
+}
+
+/// A proxy through which a master is reached on its external address, e.g. a corporate proxy
+/// or a websocket tunnel client.
+#[cfg_attr(feature = "serde", derive(Deserialize,Serialize))]
+#[cfg_attr(feature = "serde", serde(tag = "kind", rename_all = "snake_case"))]
+#[derive(Debug, Clone, PartialEq)]
+pub enum Proxy {
+    Socks5 { host: String, port: u16 },
+    Http { host: String, port: u16 },
+    /// `%h` and `%p` are replaced by the external host and port of the master.
+    Command { command: String },
+}
+
+impl Proxy {
+    pub fn hop(&self) -> Hop {
+        match self {
+            Proxy::Socks5 { host, port } => Hop::socks5(host.clone(), *port),
+            Proxy::Http { host, port } => Hop::http_connect(host.clone(), *port),
+            Proxy::Command { command } => Hop::command(command.clone()),
+        }
+    }
+}
+
 impl Host {
+    #[allow(clippy::too_many_arguments)]
     pub fn new(
         name: String,
         user: String,
@@ -57,8 +157,15 @@ impl Host {
         Self {
             name,
             ip,
+            ipv6: None,
             port,
             eport,
+            priority: None,
+            host_key: None,
+            via: Vec::new(),
+            relay: None,
+            proxy: None,
+            ssh_options: BTreeMap::new(),
             user,
             aliases,
             #[cfg(feature = "wake")]
@@ -74,16 +181,49 @@ impl Host {
         self.eport.is_some()
     }
 
-    pub fn get_hop(&self, subnet: Option<&Subnet>) -> Hop {
+    /// Hop to the host: the private one without `subnet`, otherwise the external one through
+    /// the preferred endpoint of `subnet`.
+    pub fn get_hop(&self, subnet: Option<&Subnet>) -> Result<Hop, RoutingError> {
         match subnet {
-            Some(s) => match self.eport {
-                Some(p) => Hop::new(self.user.clone(), s.subdomain.clone(), p),
-                None => panic!("cannot generate external hop for non-master hosts"),
-            },
-            None => Hop::new(self.user.clone(), self.ip.to_string(), self.port),
+            Some(s) => self.get_external_hop(&s.get_endpoint()),
+            None => Ok(Hop::new(self.user.clone(), self.ip.to_string(), self.port)),
+        }
+    }
+
+    /// External hop to the host through `endpoint` of its subnet.
+    pub fn get_external_hop(&self, endpoint: &Endpoint) -> Result<Hop, RoutingError> {
+        match self.eport {
+            Some(p) => Ok(Hop::new(self.user.clone(), endpoint.host.clone(), endpoint.port.unwrap_or(p))),
+            None => Err(RoutingError::NotAMaster(self.name.clone())),
         }
     }
 
+    /// Hop to the proxy in front of the external address of the host, if any.
+    pub fn get_proxy_hop(&self) -> Option<Hop> {
+        self.proxy.as_ref().map(Proxy::hop)
+    }
+
+    /// Hop to the host through its reverse tunnel, from the relay host.
+    pub fn get_relay_hop(&self) -> Option<Hop> {
+        self.relay
+            .as_ref()
+            .map(|r| Hop::new(self.user.clone(), r.bind.clone(), r.port))
+    }
+
+    /// Hop to the global IPv6 address of the host, if it has one.
+    pub fn get_ipv6_hop(&self) -> Option<Hop> {
+        self.ipv6
+            .filter(is_global_ipv6)
+            .map(|ip| Hop::new(self.user.clone(), ip.to_string(), self.port))
+    }
+
+    /// Checks the pinned `host_key`, if any, against the given fingerprints.
+    pub fn matches_host_key(&self, fingerprints: &[String]) -> bool {
+        self.host_key
+            .as_ref()
+            .is_none_or(|k| fingerprints.iter().any(|f| same_fingerprint(k, f)))
+    }
+
     pub fn identity(&self) -> String {
         format!("{}@{}", self.user, self.ip)
     }
@@ -0,0 +1,249 @@
+//! Information about the local machine's network, read from `/proc/net`.
+//!
+//! Where procfs is not available, the interfaces and their addresses are read with
+//! `getifaddrs` instead, while gateways and neighbours are unknown. Every function returns an
+//! empty result when the information is not available, so callers can always fall back to
+//! network based detection.
+#[cfg(not(feature = "log"))]
+use crate::debug;
+#[cfg(feature = "log")]
+use log::debug;
+use std::fs::read_to_string;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::net::cache::digest;
+
+/// Snapshot of the local network configuration.
+#[derive(Debug, Default, Clone)]
+pub struct LocalNetwork {
+    pub addrs: Vec<IpAddr>,
+    pub gateways: Vec<IpAddr>,
+    // hardware addresses of the default gateways, as found in the neighbour table
+    pub gateway_macs: Vec<String>,
+}
+
+impl LocalNetwork {
+    pub fn read() -> LocalNetwork {
+        if !std::path::Path::new("/proc/net").exists() {
+            debug!("/proc/net is not available, the gateways are unknown");
+        }
+        let gateways = default_gateways();
+        let neighbours = parse_arp(&read_to_string("/proc/net/arp").unwrap_or_default());
+        let gateway_macs = neighbours
+            .into_iter()
+            .filter(|(ip, _)| gateways.contains(ip))
+            .map(|(_, mac)| mac)
+            .collect();
+        LocalNetwork { addrs: interface_addrs(), gateways, gateway_macs }
+    }
+
+    /// Cheap identifier of the network the client is connected to, built from the interface
+    /// addresses and the default gateways.
+    pub fn fingerprint(&self) -> String {
+        let mut parts: Vec<String> = self.addrs.iter().chain(self.gateways.iter()).map(|a| a.to_string()).collect();
+        parts.extend(self.gateway_macs.iter().cloned());
+        parts.sort();
+        digest(&parts.join(" "))
+    }
+
+    /// Whether the client has global IPv6 connectivity: a global address and a default route.
+    pub fn has_global_ipv6(&self) -> bool {
+        self.addrs.iter().any(|a| matches!(a, IpAddr::V6(v6) if is_global_ipv6(v6)))
+            && self.gateways.iter().any(IpAddr::is_ipv6)
+    }
+}
+
+/// Whether `ip` is a global unicast address (`2000::/3`).
+pub fn is_global_ipv6(ip: &Ipv6Addr) -> bool {
+    ip.segments()[0] & 0xe000 == 0x2000
+}
+
+/// Addresses assigned to the local interfaces.
+pub fn interface_addrs() -> Vec<IpAddr> {
+    match read_to_string("/proc/net/fib_trie") {
+        Ok(fib_trie) => {
+            let mut addrs = parse_fib_trie(&fib_trie);
+            addrs.append(&mut parse_if_inet6(&read_to_string("/proc/net/if_inet6").unwrap_or_default()));
+            addrs
+        }
+        Err(_) => {
+            let mut addrs: Vec<IpAddr> = read_ifaddrs().into_iter().filter_map(|(_, a)| a).collect();
+            addrs.dedup();
+            addrs
+        }
+    }
+}
+
+/// Names of the network interfaces.
+pub fn interface_names() -> Vec<String> {
+    match read_to_string("/proc/net/dev") {
+        Ok(dev) => parse_dev(&dev),
+        Err(_) => {
+            let mut names: Vec<String> = read_ifaddrs().into_iter().map(|(n, _)| n).collect();
+            names.sort();
+            names.dedup();
+            names
+        }
+    }
+}
+
+/// Interfaces names along with their addresses, as returned by `getifaddrs`. Interfaces appear
+/// once per address, and with no address for entries of other families.
+#[cfg(unix)]
+pub fn read_ifaddrs() -> Vec<(String, Option<IpAddr>)> {
+    use std::ffi::CStr;
+
+    let mut head: *mut libc::ifaddrs = std::ptr::null_mut();
+    // SAFETY: on success the list is owned by us until freeifaddrs
+    if unsafe { libc::getifaddrs(&mut head) } != 0 {
+        debug!("getifaddrs failed: {}", std::io::Error::last_os_error());
+        return vec![];
+    }
+    let mut entries = vec![];
+    let mut current = head;
+    while !current.is_null() {
+        // SAFETY: current is a non null node of the list, whose name is a valid C string
+        let ifa = unsafe { &*current };
+        let name = unsafe { CStr::from_ptr(ifa.ifa_name) }.to_string_lossy().into_owned();
+        entries.push((name, unsafe { sockaddr_ip(ifa.ifa_addr) }));
+        current = ifa.ifa_next;
+    }
+    // SAFETY: head comes from getifaddrs and none of its nodes is used afterwards
+    unsafe { libc::freeifaddrs(head) };
+    entries
+}
+
+#[cfg(not(unix))]
+pub fn read_ifaddrs() -> Vec<(String, Option<IpAddr>)> {
+    vec![]
+}
+
+/// # Safety
+/// `addr` must be null or point to a socket address whose size matches its family.
+#[cfg(unix)]
+unsafe fn sockaddr_ip(addr: *const libc::sockaddr) -> Option<IpAddr> {
+    if addr.is_null() {
+        return None;
+    }
+    match (*addr).sa_family as libc::c_int {
+        libc::AF_INET => {
+            let addr = &*(addr as *const libc::sockaddr_in);
+            Some(IpAddr::V4(Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr))))
+        }
+        libc::AF_INET6 => {
+            let addr = &*(addr as *const libc::sockaddr_in6);
+            Some(IpAddr::V6(Ipv6Addr::from(addr.sin6_addr.s6_addr)))
+        }
+        _ => None,
+    }
+}
+
+/// Next hops of the default routes, both IPv4 and IPv6.
+pub fn default_gateways() -> Vec<IpAddr> {
+    let mut gateways = parse_route(&read_to_string("/proc/net/route").unwrap_or_default());
+    gateways.append(&mut parse_ipv6_route(&read_to_string("/proc/net/ipv6_route").unwrap_or_default()));
+    gateways
+}
+
+/// Extracts the local IPv4 addresses from the content of `/proc/net/fib_trie`.
+pub fn parse_fib_trie(content: &str) -> Vec<IpAddr> {
+    let mut addrs = vec![];
+    let mut last = None;
+    for line in content.lines().map(str::trim) {
+        if let Some(ip) = line.strip_prefix("|-- ") {
+            last = ip.parse::<Ipv4Addr>().ok();
+        } else if line.starts_with("/32 host LOCAL") {
+            if let Some(ip) = last.take().map(IpAddr::V4) {
+                if !addrs.contains(&ip) {
+                    addrs.push(ip);
+                }
+            }
+        }
+    }
+    addrs
+}
+
+/// Extracts the local IPv6 addresses from the content of `/proc/net/if_inet6`.
+pub fn parse_if_inet6(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .filter_map(|l| l.split_whitespace().next())
+        .filter_map(parse_hex_ipv6)
+        .map(IpAddr::V6)
+        .collect()
+}
+
+/// Extracts the interface names from the content of `/proc/net/dev`.
+pub fn parse_dev(content: &str) -> Vec<String> {
+    content
+        .lines()
+        .filter_map(|l| l.split_once(':'))
+        .map(|(name, _)| name.trim().to_owned())
+        .filter(|name| !name.contains('|'))
+        .collect()
+}
+
+/// Extracts the default gateways from the content of `/proc/net/route`.
+pub fn parse_route(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, "00000000", gateway, ..] => u32::from_str_radix(gateway, 16)
+                    .ok()
+                    .filter(|g| *g != 0)
+                    .map(|g| IpAddr::V4(Ipv4Addr::from(g.to_le_bytes()))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Extracts the default gateways from the content of `/proc/net/ipv6_route`.
+pub fn parse_ipv6_route(content: &str) -> Vec<IpAddr> {
+    content
+        .lines()
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.as_slice() {
+                [_, "00", _, _, next_hop, ..] => parse_hex_ipv6(next_hop)
+                    .filter(|g| !g.is_unspecified())
+                    .map(IpAddr::V6),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Extracts the resolved neighbours from the content of `/proc/net/arp`, with normalized macs.
+pub fn parse_arp(content: &str) -> Vec<(IpAddr, String)> {
+    content
+        .lines()
+        .skip(1)
+        .filter_map(|l| {
+            let fields: Vec<&str> = l.split_whitespace().collect();
+            match fields.as_slice() {
+                // flag 0x0 marks incomplete entries
+                [ip, _, flags, mac, ..] if *flags != "0x0" => ip
+                    .parse()
+                    .ok()
+                    .map(|ip| (ip, normalize_mac(mac))),
+                _ => None,
+            }
+        })
+        .collect()
+}
+
+/// Lowercases a mac address and uses `:` as separator, so that it can be compared.
+pub fn normalize_mac(mac: &str) -> String {
+    mac.trim().to_lowercase().replace('-', ":")
+}
+
+fn parse_hex_ipv6(s: &str) -> Option<Ipv6Addr> {
+    if s.len() != 32 {
+        return None;
+    }
+    u128::from_str_radix(s, 16).ok().map(Ipv6Addr::from)
+}
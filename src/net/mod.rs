@@ -1,8 +1,13 @@
+pub mod cache;
+pub mod detector;
 mod host;
 mod networkmap;
 mod subnet;
-mod external_ip;
+pub mod external_ip;
+pub mod local;
+pub mod probe;
 
-pub use host::Host;
-pub use networkmap::{NetworkMap,NetworkMapError,ConnectionMethod};
-pub use subnet::Subnet;
+pub use host::{Host, Proxy, Relay};
+pub use networkmap::{NetworkMap,NetworkMapError,RoutingError,ConnectionMethod};
+pub use subnet::{Endpoint, Entry, Subnet};
+pub use probe::Probe;
@@ -6,33 +6,51 @@ use serde::{ser::SerializeSeq, Deserialize, Serialize};
 
 #[cfg(not(feature = "log"))]
 use crate::{debug, info, warn};
-use crate::net::external_ip::get_ip;
 #[cfg(feature = "log")]
 use log::{debug, info, warn};
-use reachable::{IcmpTarget, ResolvePolicy, Status, Target, TcpTarget};
+use futures::future::join_all;
 use std::collections::{HashMap, HashSet};
 use std::fmt::Display;
-use std::net::{IpAddr, Ipv4Addr, ToSocketAddrs};
+use std::net::IpAddr;
+use std::path::Path;
+#[cfg(feature = "direct")]
+use std::pin::pin;
 use std::time::Duration;
-#[cfg(feature = "wake")]
-use subprocess::ExitStatus;
+use tokio::net::lookup_host;
+#[cfg(feature = "direct")]
+use tokio::time::sleep;
+use tokio::time::timeout;
 #[cfg(feature = "sshfs")]
 use crate::cmd::sshfs::SSHFSProcess;
 
-use crate::net::{Host, Subnet};
+use crate::config::{RouteSelection, Settings};
+use crate::net::{Entry, Host, Probe, Subnet};
+use crate::net::cache::{digest, DetectionCache};
+use crate::net::detector::SubnetDetector;
+use crate::net::local::LocalNetwork;
+use crate::net::probe::first_reachable;
 use crate::ssh::{options::*, *};
 use crate::ssh::process::Process;
 #[cfg(feature = "wake")]
-use crate::waker::Waker;
-
-const CLOUD_FLARE: IpAddr = IpAddr::V4(Ipv4Addr::new(1, 1, 1, 1));
+use crate::ssh::{process::Stdout, runner::Runner};
+#[cfg(feature = "wake")]
+use crate::waker::{Waker, WakeError};
 
 #[derive(Debug, Default)]
 pub struct NetworkMap {
     subnets: HashMap<String, Subnet>,
+    settings: Settings,
 }
 
 impl NetworkMap {
+    pub fn settings(&self) -> &Settings {
+        &self.settings
+    }
+
+    pub fn settings_mut(&mut self) -> &mut Settings {
+        &mut self.settings
+    }
+
     pub fn add_subnet(&mut self, s: Subnet) {
         self.subnets.insert(s.subdomain.clone(), s);
     }
@@ -47,6 +65,10 @@ impl NetworkMap {
         None
     }
 
+    pub fn get_host_mut(&mut self, q: &str) -> Option<&mut Host> {
+        self.subnets.values_mut().find_map(|s| s.get_host_mut(q))
+    }
+
     pub fn check(&self) -> Result<(), NetworkMapError> {
         let mut subs = HashSet::new();
         let mut host_aliases: HashSet<String> = HashSet::new();
@@ -78,130 +100,661 @@ impl NetworkMap {
         if sync.len() > 1 {
             return Err(NetworkMapError::MultipleSyncHosts(sync));
         }
+        for s in self.subnets.values() {
+            self.check_behind_chain(s)?;
+            for h in s.get_hosts() {
+                self.check_via(h)?;
+                self.check_relay_chain(h)?;
+            }
+            if let Some(l) = s.links.iter().find(|l| !self.subnets.contains_key(*l)) {
+                return Err(NetworkMapError::UnknownLink(s.subdomain.clone(), l.clone()));
+            }
+        }
         Ok(())
     }
 
-    pub fn is_available(ip: impl ToString, port: Option<u16>) -> bool {
-        let rp = ResolvePolicy::ResolveToIPv4;
-        match match port {
-            Some(p) => TcpTarget::new(ip.to_string(), p, Duration::from_millis(2000), rp)
-                .check_availability(),
-            None => IcmpTarget::new(ip.to_string(), rp).check_availability(),
-        } {
-            Ok(r) => matches!(r, Status::Available),
-            Err(_) => false,
+    /// Makes sure every host in the `via` list of `h` exists and that `h` isn't part of it.
+    fn check_via(&self, h: &Host) -> Result<(), RoutingError> {
+        for name in h.via.iter() {
+            match self.get_host(name) {
+                None => return Err(RoutingError::UnknownVia(h.name.clone(), name.clone())),
+                Some(v) if v == h => {
+                    let mut chain = h.via.clone();
+                    chain.push(h.name.clone());
+                    return Err(RoutingError::RoutingCycle(chain));
+                }
+                Some(_) => {}
+            }
         }
+        Ok(())
     }
 
-    pub fn get_subnet_by_ip(&self, ip: IpAddr) -> Option<&Subnet> {
-        self.subnets.values().find(|s| {
-            if s.eip.is_some() {
-                s.eip.unwrap() == ip
-            } else {
-                format!("{}:0", s.subdomain)
-                    .to_socket_addrs()
-                    .unwrap()
-                    .next()
-                    .unwrap()
-                    .ip()
-                    == ip
+    /// Follows the chain of relays starting from `h`, making sure every relay exists and that
+    /// the chain doesn't loop back on itself.
+    fn check_relay_chain(&self, h: &Host) -> Result<(), RoutingError> {
+        let mut chain = vec![h.name.clone()];
+        let mut current = h;
+        while let Some(r) = &current.relay {
+            current = self
+                .get_host(&r.host)
+                .ok_or_else(|| RoutingError::UnknownRelay(current.name.clone(), r.host.clone()))?;
+            let looped = chain.contains(&current.name);
+            chain.push(current.name.clone());
+            if looped {
+                return Err(RoutingError::RoutingCycle(chain));
             }
-        })
+        }
+        Ok(())
     }
 
-    pub fn get_masters(&self) -> Vec<(&Subnet, &Host)> {
-        self.subnets.values().map(|s| (s, s.get_master())).collect()
+    /// Follows the `behind` chain starting from `s`, making sure every gateway exists and
+    /// that the chain doesn't loop back on itself.
+    fn check_behind_chain(&self, s: &Subnet) -> Result<(), RoutingError> {
+        let mut chain = vec![s.subdomain.clone()];
+        let mut current = s;
+        while let Some(g) = &current.behind {
+            let gateway = match self.get_host(g) {
+                Some(h) => h,
+                None => return Err(RoutingError::UnknownGateway(current.subdomain.clone(), g.clone())),
+            };
+            current = self.get_host_subnet(gateway)?;
+            let looped = chain.contains(&current.subdomain);
+            chain.push(current.subdomain.clone());
+            if looped {
+                return Err(RoutingError::RoutingCycle(chain));
+            }
+        }
+        Ok(())
     }
 
-    /// Gets master of given host
-    pub fn get_host_subnet(&self, h: &Host) -> &Subnet {
-        for s in self.subnets.values() {
-            if s.has_host(h) {
-                return s;
+    /// Timeout of the reachability probes, from the settings.
+    pub fn probe_timeout(&self) -> Duration {
+        Duration::from_millis(self.settings.probe_timeout)
+    }
+
+    /// Resolves the endpoints of the subnets without an `eip`, concurrently and within
+    /// `resolve_timeout`. Both A and AAAA records are considered. Subdomains that cannot be
+    /// resolved are skipped with a warning. When `cache_path` is set, results are cached.
+    pub async fn resolve_subdomains(&self) -> HashMap<&str, Vec<IpAddr>> {
+        let cache = self.settings.cache_path.as_deref().filter(|_| self.settings.cache_ttl > 0);
+        let cached = cache.map(DetectionCache::load).unwrap_or_default();
+        let mut resolved = HashMap::new();
+        let mut pending = vec![];
+        for d in self.subnets.values().filter(|s| s.eip.is_none()).flat_map(Subnet::endpoint_hosts) {
+            if resolved.contains_key(d) || pending.contains(&d) {
+                continue;
             }
+            match cached.get_resolved(d, self.settings.cache_ttl) {
+                Some(addrs) => {
+                    debug!("using cached addresses of {}: {:?}", d, addrs);
+                    resolved.insert(d, addrs.to_vec());
+                }
+                None => pending.push(d),
+            }
+        }
+
+        let limit = Duration::from_millis(self.settings.resolve_timeout);
+        let results = join_all(pending.iter().map(|d| resolve(d, limit))).await;
+        let mut fresh = vec![];
+        for (d, r) in pending.into_iter().zip(results) {
+            let Some(addrs) = r else {
+                warn!("cannot resolve subdomain {}, skipping its subnet", d);
+                continue;
+            };
+            debug!("subdomain {} resolved to {:?}", d, addrs);
+            fresh.push((d, addrs.clone()));
+            resolved.insert(d, addrs);
         }
-        panic!("host is not in any subnet")
+        if let Some(path) = cache.filter(|_| !fresh.is_empty()) {
+            self.update_cache(path, |c| {
+                for (d, addrs) in fresh {
+                    c.set_resolved(d, addrs);
+                }
+            });
+        }
+        resolved
     }
 
-    pub fn get_host_master(&self, h: &Host) -> &Host {
-        self.get_host_subnet(h).get_master()
+    /// Matches the external address of the client against the subnets, using the `resolved`
+    /// endpoints of those without an `eip`.
+    pub fn match_external_ip(&self, ip: IpAddr, resolved: &HashMap<&str, Vec<IpAddr>>) -> Option<&Subnet> {
+        self.subnets.values().find(|s| {
+            let addrs: Vec<IpAddr> = s
+                .endpoint_hosts()
+                .into_iter()
+                .filter_map(|d| resolved.get(d))
+                .flatten()
+                .copied()
+                .collect();
+            s.matches_external_ip(ip, &addrs)
+        })
     }
 
-    /// Gets client external ip and returns the optional matched subnet in which the client is.
-    pub async fn find_current_subnet(&self) -> Option<&Subnet> {
-        // are we online?
-        if NetworkMap::is_available(CLOUD_FLARE, Some(80)) {
-            debug!("network: we are online");
-            debug!("getting external ip");
-            match get_ip().await {
-                Some(client_eip) => {
-                    info!("external ip is {}", client_eip);
-                    self.get_subnet_by_ip(client_eip)
-                },
-                None => {
-                    warn!("cannot get external ip");
-                    None
-                },
+    pub async fn get_subnet_by_ip(&self, ip: IpAddr) -> Option<&Subnet> {
+        self.match_external_ip(ip, &self.resolve_subdomains().await)
+    }
+
+    pub fn get_subnet(&self, subdomain: &str) -> Option<&Subnet> {
+        self.subnets.get(subdomain)
+    }
+
+    pub fn get_subnets(&self) -> Vec<&Subnet> {
+        self.subnets.values().collect()
+    }
+
+    pub fn get_masters(&self) -> Vec<(&Subnet, &Host)> {
+        self.subnets
+            .values()
+            .flat_map(|s| s.get_masters().into_iter().map(move |m| (s, m)))
+            .collect()
+    }
+
+    /// Gets subnet of given host
+    pub fn get_host_subnet(&self, h: &Host) -> Result<&Subnet, RoutingError> {
+        self.subnets
+            .values()
+            .find(|s| s.has_host(h))
+            .ok_or_else(|| RoutingError::NotInSubnet(h.name.clone()))
+    }
+
+    pub fn get_host_master(&self, h: &Host) -> Result<&Host, RoutingError> {
+        self.get_host_subnet(h)?.get_master()
+    }
+
+    /// Runs `detectors` in order: the first one recognizing at least a subnet with a confidence
+    /// of at least `detection_threshold` wins. The client can be in several subnets at once (e.g.
+    /// a LAN and a VPN), so all of its confident detections are returned, most confident first.
+    /// Less confident detections are only logged.
+    pub async fn detect_subnets(&self, detectors: &[Box<dyn SubnetDetector>]) -> Vec<&Subnet> {
+        for d in detectors {
+            debug!("running subnet detector {}", d.name());
+            let detections = d.detect(self).await;
+            #[cfg(feature = "log")]
+            for det in detections.iter() {
+                debug!("detector {}: subnet {} with confidence {}", d.name(), det.subnet.subdomain, det.confidence);
             }
-        } else {
-            debug!("network: we are offline");
-            info!("no internet connection detected");
-            debug!("detecting subnet using masters...");
-            // no, check if some network master is available
-            for (s, m) in self.get_masters() {
-                if NetworkMap::is_available(m.ip, Some(m.port)) {
-                    return Some(s);
+            let mut detections: Vec<_> = detections
+                .into_iter()
+                .filter(|det| det.confidence >= self.settings.detection_threshold)
+                .collect();
+            if detections.is_empty() {
+                continue;
+            }
+            detections.sort_by_key(|det| std::cmp::Reverse(det.confidence));
+            let mut subnets: Vec<&Subnet> = vec![];
+            for det in detections {
+                if !subnets.contains(&det.subnet) {
+                    info!("detector {} matched subnet {}", d.name(), det.subnet.subdomain);
+                    subnets.push(det.subnet);
                 }
-                debug!("{} is unavailable", m.get_hop(Some(s)));
             }
-            warn!("no internet connection, and not in a known subnet");
-            None
+            return subnets;
         }
+        warn!("not in a known subnet");
+        vec![]
     }
 
-    pub async fn hops_gen(&self, target: &Host, subnet: Option<&Subnet>) -> (Hop, Vec<Hop>) {
-        fn actual(t_s: &Subnet) -> Vec<Hop> {
-            let master = t_s.get_master();
-            vec![master.get_hop(Some(t_s))]
+    /// Detects the subnets in which the client is, using the detectors chain in the settings.
+    /// When `cache_path` is set, the result is cached for the current local network and
+    /// detection settings, unless no subnet is detected.
+    pub async fn find_current_subnets(&self) -> Vec<&Subnet> {
+        let cache = self.detection_key();
+        if let Some((path, key)) = &cache {
+            let cached = DetectionCache::load(path)
+                .get_subnets(key, self.settings.cache_ttl)
+                .and_then(|subdomains| subdomains.iter().map(|s| self.get_subnet(s)).collect::<Option<Vec<_>>>());
+            if let Some(subnets) = cached {
+                info!("using cached detection: {:?}", subnets.iter().map(|s| &s.subdomain).collect::<Vec<_>>());
+                return subnets;
+            }
+        }
+        let detectors: Vec<Box<dyn SubnetDetector>> = self.settings.detectors.iter().map(|d| d.build()).collect();
+        let subnets = self.detect_subnets(&detectors).await;
+        match &cache {
+            // the network may still be coming up, the next run will try again
+            Some(_) if subnets.is_empty() => debug!("nothing detected, not caching"),
+            Some((path, key)) => self.update_cache(path, |c| c.set_subnets(key, subnets.iter().map(|s| s.subdomain.clone()).collect())),
+            None => {}
         }
+        subnets
+    }
 
-        let target_subnet = self.get_host_subnet(target);
-        let target_hop = target.get_hop(if let Some(s) = subnet {
-            if target_subnet != s && target.is_master() {
-                Some(target_subnet)
-            } else {
-                None
+    /// Path of the detection cache and key of the detected subnets: the current local network,
+    /// along with the detection settings the result depends on.
+    pub fn detection_key(&self) -> Option<(&Path, String)> {
+        self.cache_key().map(|(path, network)| {
+            #[cfg(feature = "serde")]
+            let chain = serde_json::to_string(&self.settings.detectors).unwrap_or_default();
+            // the cache is not saved without serde anyway
+            #[cfg(not(feature = "serde"))]
+            let chain = String::new();
+            let settings = format!("{} {} {}", chain, self.settings.detection_threshold, self.settings.external_lookups);
+            (path, format!("{}-{}", network, digest(&settings)))
+        })
+    }
+
+    /// Path of the detection cache and key of the current local network, when caching is enabled.
+    pub fn cache_key(&self) -> Option<(&Path, String)> {
+        match &self.settings.cache_path {
+            Some(p) if self.settings.cache_ttl > 0 => Some((p.as_path(), LocalNetwork::read().fingerprint())),
+            _ => None,
+        }
+    }
+
+    pub fn update_cache(&self, path: &Path, f: impl FnOnce(&mut DetectionCache)) {
+        let mut cache = DetectionCache::load(path);
+        f(&mut cache);
+        #[cfg_attr(not(feature = "log"), allow(unused_variables))]
+        if let Err(e) = cache.save(path, self.settings.cache_ttl) {
+            warn!("cannot save detection cache to {:?}: {}", path, e);
+        }
+    }
+
+    /// Selects the master and endpoint to enter `s` from outside: the entries (each master by
+    /// priority, through each endpoint) are probed in order and the first reachable one is
+    /// chosen, falling back to the preferred one if none answers. With
+    /// [`RouteSelection::Latency`] all entries are probed at once and the fastest one wins.
+    ///
+    /// Masters behind a proxy can't be probed: they are chosen when no entry preferred to them
+    /// answers, or, by latency, when no entry answers at all.
+    pub async fn select_entry<'a>(&self, s: &'a Subnet) -> Result<Entry<'a>, RoutingError> {
+        let mut entries = s.get_entries();
+        let unprobed = entries.iter().position(Entry::is_proxied);
+        if entries.len() > 1 && self.settings.route_selection == RouteSelection::Latency {
+            let probed: Vec<usize> = (0..entries.len()).filter(|&i| !entries[i].is_proxied()).collect();
+            let probes = join_all(probed.iter().map(|&i| self.probe_entry(&entries[i]))).await;
+            #[cfg(feature = "log")]
+            for p in probes.iter() {
+                debug!("router: probe {}", p);
+            }
+            if let Some(i) = fastest(&probes) {
+                return Ok(selected(s, entries.swap_remove(probed[i])));
+            }
+        } else if entries.len() > 1 {
+            let targets: Vec<(String, u16)> = entries[..unprobed.unwrap_or(entries.len())].iter().map(Entry::address).collect();
+            if let Some(i) = first_reachable(&targets, self.probe_timeout()).await {
+                return Ok(selected(s, entries.swap_remove(i)));
             }
-        } else if target.is_master() {
-                Some(target_subnet)
+        }
+        if let Some(i) = unprobed.filter(|_| entries.len() > 1) {
+            debug!("router: master {} of subnet {} is behind a proxy, choosing it without probing", entries[i].master.name, s.subdomain);
+            return Ok(selected(s, entries.swap_remove(i)));
+        }
+        if entries.len() > 1 {
+            warn!("router: no master of subnet {} is reachable, falling back to the preferred one", s.subdomain);
+        }
+        if entries.is_empty() {
+            return Err(RoutingError::NoMaster(s.subdomain.clone()));
+        }
+        Ok(selected(s, entries.swap_remove(0)))
+    }
+
+    /// Generates the hop for `target` and the jump hosts needed to reach it from the `current`
+    /// subnets, following the `behind` chain of the target's subnet as deep as needed, or its
+    /// `relay`. No jump is needed to enter any of the `current` subnets.
+    pub async fn hops_gen(&self, target: &Host, current: &[&Subnet]) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        self.hops_gen_through(target, current, None).await
+    }
+
+    /// Same as [`NetworkMap::hops_gen`], but the top level subnet is entered through `entry`
+    /// instead of the one picked by [`NetworkMap::select_entry`].
+    pub async fn hops_gen_through(
+        &self,
+        target: &Host,
+        current: &[&Subnet],
+        entry: Option<&Entry<'_>>,
+    ) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        self.check_behind_chain(self.get_host_subnet(target)?)?;
+        if !target.via.is_empty() {
+            return self.via_route(target, current);
+        }
+        if entry.is_some() {
+            return self.host_route(target, current, entry);
+        }
+        if let Some(hop) = self.ipv6_hop(target, current)? {
+            return Ok((hop, vec![]));
+        }
+        if let Some(relay) = self.relay_host(target, current)? {
+            return self.relay_route(target, relay, current).await;
+        }
+        match self.entry_subnet(target, current)? {
+            Some(s) => self.host_route(target, current, Some(&self.select_entry(s).await?)),
+            None => self.host_route(target, current, None),
+        }
+    }
+
+    /// The relay host through which `target` must be reached, if it has a relay and its private
+    /// address is not reachable from the `current` subnets.
+    fn relay_host(&self, target: &Host, current: &[&Subnet]) -> Result<Option<&Host>, RoutingError> {
+        let Some(relay) = &target.relay else {
+            return Ok(None);
+        };
+        if self.reaches(current, self.get_host_subnet(target)?) {
+            return Ok(None);
+        }
+        self.check_relay_chain(target)?;
+        match self.get_host(&relay.host) {
+            Some(h) => Ok(Some(h)),
+            None => Err(RoutingError::UnknownRelay(target.name.clone(), relay.host.clone())),
+        }
+    }
+
+    /// Route to `target` through the reverse tunnel it keeps open on `relay`: the relay is
+    /// reached as any other host, then the tunnel is entered from there.
+    async fn relay_route(&self, target: &Host, relay: &Host, current: &[&Subnet]) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        debug!("router: {} is reached through its reverse tunnel on {}", target.name, relay.name);
+        let (relay_hop, mut hops) = Box::pin(self.hops_gen(relay, current)).await?;
+        hops.push(relay_hop);
+        // a relay is always set here
+        let hop = target.get_relay_hop().ok_or_else(|| RoutingError::UnknownRelay(target.name.clone(), relay.name.clone()))?;
+        Ok((self.with_options(target, hop)?, hops))
+    }
+
+    /// Route through the hosts in the `via` list of `target`, in place of the computed one. Each
+    /// host is reached on its external address when it's the master of a subnet the client (or
+    /// the previous host) is not in, and on its private one otherwise.
+    fn via_route(&self, target: &Host, current: &[&Subnet]) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        self.check_via(target)?;
+        debug!("router: {} is routed via {}", target.name, target.via.join(", "));
+        let mut inside = current.to_vec();
+        let mut hops = vec![];
+        for name in target.via.iter() {
+            let h = self.get_host(name).ok_or_else(|| RoutingError::UnknownVia(target.name.clone(), name.clone()))?;
+            let hop = self.via_hop(h, &inside, &mut hops)?;
+            hops.push(hop);
+            inside = vec![self.get_host_subnet(h)?];
+        }
+        let hop = self.via_hop(target, &inside, &mut hops)?;
+        Ok((hop, hops))
+    }
+
+    /// Hop to `h` from the `inside` subnets, its proxy is pushed to `hops` when needed.
+    fn via_hop(&self, h: &Host, inside: &[&Subnet], hops: &mut Vec<Hop>) -> Result<Hop, RoutingError> {
+        let s = self.get_host_subnet(h)?;
+        if !self.reaches(inside, s) && h.is_master() && s.behind.is_none() {
+            hops.extend(h.get_proxy_hop());
+            self.with_options(h, h.get_hop(Some(s))?)
         } else {
-            None
-        });
+            self.with_options(h, h.get_hop(None)?)
+        }
+    }
 
-        let hops= if target.is_master() {
-            debug!("router: target is master, connecting directly");
-            vec![]
+    /// Hop to the global IPv6 address of `target`, when the client is outside its subnet but
+    /// has IPv6 connectivity, so that no jump host is needed.
+    fn ipv6_hop(&self, target: &Host, current: &[&Subnet]) -> Result<Option<Hop>, RoutingError> {
+        if self.reaches(current, self.get_host_subnet(target)?) {
+            return Ok(None);
+        }
+        match target.get_ipv6_hop() {
+            Some(hop) if LocalNetwork::read().has_global_ipv6() => {
+                debug!("router: {} has a global ipv6 address, connecting directly", target.name);
+                Ok(Some(self.with_options(target, hop)?))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    async fn probe_entry(&self, e: &Entry<'_>) -> Probe {
+        let (host, port) = e.address();
+        Probe::tcp(&host, port, self.probe_timeout()).await
+    }
+
+    /// Chooses how to connect to `target`, detecting the current subnet when needed.
+    ///
+    /// With [`RouteSelection::Latency`] every candidate first hop (direct addresses, the route
+    /// through the current subnet or each master of the subnet to enter) is probed concurrently
+    /// and the fastest one is chosen. The probes are returned along with the chosen method.
+    /// Masters behind a proxy can't be probed, they are chosen only when no candidate answers.
+    pub async fn plan_connection<'a>(&'a self, target: &'a Host) -> Result<(ConnectionMethod<'a>, Vec<Probe>), RoutingError> {
+        if !target.via.is_empty() {
+            debug!("router: {} has an explicit route, nothing to select", target.name);
+            return Ok((ConnectionMethod::ViaSubnet(self.find_current_subnets().await), vec![]));
+        }
+        if self.settings.route_selection == RouteSelection::Static {
+            // detected beforehand, so that the routed path races on its connection time only
+            let current = self.find_current_subnets().await;
+            #[cfg(feature = "direct")]
+            match &target.addrs {
+                Some(addrs) if !addrs.is_empty() => return self.race_connection(target, addrs, current).await,
+                _ => debug!("direct ips not set"),
+            }
+            return Ok((ConnectionMethod::ViaSubnet(current), vec![]));
+        }
+
+        let current = self.find_current_subnets().await;
+        let mut methods = vec![];
+        let mut candidates = vec![];
+        // masters behind a proxy can't be probed, they are used when no candidate answers
+        let mut unprobed = vec![];
+        #[cfg(feature = "direct")]
+        for addr in target.addrs.iter().flatten() {
+            methods.push(ConnectionMethod::Direct(addr.clone()));
+            candidates.push((addr.clone(), target.port));
+        }
+        let ipv6 = self.ipv6_hop(target, &current)?;
+        if let Some(hop) = &ipv6 {
+            methods.push(ConnectionMethod::ViaSubnet(current.clone()));
+            candidates.push((hop.host().to_owned(), hop.port()));
+        }
+        if self.relay_host(target, &current)?.is_some() {
+            if ipv6.is_none() {
+                let (target_hop, hops) = self.hops_gen(target, &current).await?;
+                let first = hops.first().unwrap_or(&target_hop);
+                methods.push(ConnectionMethod::ViaSubnet(current.clone()));
+                candidates.push((first.host().to_owned(), first.port()));
+            }
         } else {
-            match subnet {
+            match self.entry_subnet(target, &current)? {
                 Some(s) => {
-                    debug!("router: we are in a known subnet");
-                    if target_subnet == s {
-                        debug!("router: client is in target's subnet");
-                        vec![]
-                    } else {
-                        debug!("router: client is in known subnet, a different one from the target");
-                        actual(target_subnet)
+                    for entry in s.get_entries() {
+                        if entry.is_proxied() {
+                            unprobed.push(ConnectionMethod::ViaMaster(current.clone(), entry));
+                            continue;
+                        }
+                        candidates.push(entry.address());
+                        methods.push(ConnectionMethod::ViaMaster(current.clone(), entry));
                     }
                 }
+                None if ipv6.is_none() => {
+                    let (target_hop, hops) = self.host_route(target, &current, None)?;
+                    let first = hops.first().unwrap_or(&target_hop);
+                    methods.push(ConnectionMethod::ViaSubnet(current.clone()));
+                    candidates.push((first.host().to_owned(), first.port()));
+                }
+                // the target is reached directly on its ipv6 address, already a candidate
+                None => {}
+            }
+        }
+
+        let probes = join_all(candidates.iter().map(|(h, p)| Probe::tcp(h, *p, self.probe_timeout()))).await;
+        #[cfg(feature = "log")]
+        for p in probes.iter() {
+            debug!("router: probe {}", p);
+        }
+        match fastest(&probes) {
+            Some(i) => {
+                info!("router: fastest first hop is {}", probes[i]);
+                Ok((methods.swap_remove(i), probes))
+            }
+            None if !unprobed.is_empty() => {
+                info!("router: no candidate first hop answered, entering through a master behind a proxy");
+                Ok((unprobed.swap_remove(0), probes))
+            }
+            None => {
+                warn!("router: no candidate first hop answered, falling back to static routing");
+                Ok((ConnectionMethod::ViaSubnet(current), probes))
+            }
+        }
+    }
+
+    /// Races the direct addresses of `target` against the path routed from the `current` subnets,
+    /// happy eyeballs style: the direct addresses are probed right away, while the routed path
+    /// gets going after `race_head_start`, or as soon as every direct address failed. The first
+    /// one to answer wins; when none does, the routed path is used anyway.
+    #[cfg(feature = "direct")]
+    async fn race_connection<'a>(
+        &'a self,
+        target: &'a Host,
+        addrs: &'a [String],
+        current: Vec<&'a Subnet>,
+    ) -> Result<(ConnectionMethod<'a>, Vec<Probe>), RoutingError> {
+        debug!("racing direct addresses against the routed path");
+        let targets: Vec<(String, u16)> = addrs.iter().map(|a| (a.clone(), target.port)).collect();
+        let mut direct = pin!(first_reachable(&targets, self.probe_timeout()));
+        let direct_won = |i: usize| {
+            info!("router: direct connection is available using address {}", addrs[i]);
+            Ok((ConnectionMethod::Direct(addrs[i].clone()), vec![]))
+        };
+
+        tokio::select! {
+            biased;
+            d = &mut direct => match d {
+                Some(i) => return direct_won(i),
+                None => {
+                    debug!("no direct address is available");
+                    let (route, probe) = self.probe_routed(target, &current).await?;
+                    return Ok((route, vec![probe]));
+                }
+            },
+            _ = sleep(Duration::from_millis(self.settings.race_head_start)) => {
+                debug!("direct addresses didn't answer within the head start, starting the routed path");
+            }
+        }
+
+        let mut routed = pin!(self.probe_routed(target, &current));
+        tokio::select! {
+            biased;
+            d = &mut direct => match d {
+                Some(i) => direct_won(i),
                 None => {
-                    debug!("router: we are in an unknown subnet");
-                    actual(target_subnet)
+                    debug!("no direct address is available");
+                    let (route, probe) = routed.await?;
+                    Ok((route, vec![probe]))
+                }
+            },
+            r = &mut routed => {
+                let (route, probe) = r?;
+                if probe.latency.is_some() {
+                    info!("router: routed path answered first");
+                    return Ok((route, vec![probe]));
+                }
+                match direct.await {
+                    Some(i) => direct_won(i),
+                    None => Ok((route, vec![probe])),
                 }
             }
+        }
+    }
+
+    /// Probes the first hop of the route to `target` from the `current` subnets. The selected
+    /// entry, if any, is returned with the route, so that it is not selected again.
+    #[cfg(feature = "direct")]
+    async fn probe_routed<'a>(&'a self, target: &'a Host, current: &[&'a Subnet]) -> Result<(ConnectionMethod<'a>, Probe), RoutingError> {
+        let entry = match self.entry_subnet(target, current)? {
+            Some(s) if self.ipv6_hop(target, current)?.is_none() && self.relay_host(target, current)?.is_none() => {
+                Some(self.select_entry(s).await?)
+            }
+            _ => None,
         };
-        (target_hop, hops)
+        let (target_hop, hops) = self.hops_gen_through(target, current, entry.as_ref()).await?;
+        let first = hops.first().unwrap_or(&target_hop);
+        let probe = Probe::tcp(first.host(), first.port(), self.probe_timeout()).await;
+        debug!("router: probe {}", probe);
+        let method = match entry {
+            Some(e) => ConnectionMethod::ViaMaster(current.to_vec(), e),
+            None => ConnectionMethod::ViaSubnet(current.to_vec()),
+        };
+        Ok((method, probe))
+    }
+
+    async fn connection_hops(&self, target: &Host, connection: ConnectionMethod<'_>) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        match connection {
+            #[cfg(feature = "direct")]
+            ConnectionMethod::Direct(addr) => Ok((self.with_options(target, Hop::new(target.user.clone(), addr, target.port))?, vec![])),
+            ConnectionMethod::ViaSubnet(current) => self.hops_gen(target, &current).await,
+            ConnectionMethod::ViaMaster(current, entry) => self.hops_gen_through(target, &current, Some(&entry)).await,
+        }
+    }
+
+    /// Finds the top level subnet that must be entered through one of its masters in order to
+    /// reach `h`, if any.
+    fn entry_subnet(&self, h: &Host, current: &[&Subnet]) -> Result<Option<&Subnet>, RoutingError> {
+        let h_subnet = self.get_host_subnet(h)?;
+        if self.reaches(current, h_subnet) || (h.is_master() && h_subnet.behind.is_none()) {
+            return Ok(None);
+        }
+        match &h_subnet.behind {
+            None => Ok(Some(h_subnet)),
+            Some(g) => self.entry_subnet(self.get_gateway(h_subnet, g)?, current),
+        }
     }
 
+    /// Sets the ssh options of `h` on `hop`: those of its subnet first, then its own.
+    fn with_options(&self, h: &Host, hop: Hop) -> Result<Hop, RoutingError> {
+        let mut options = self.get_host_subnet(h)?.ssh_options.clone();
+        options.extend(h.ssh_options.clone());
+        Ok(hop.with_options(options))
+    }
+
+    /// Checks whether the private addresses of `s` are reachable from the `current` subnets,
+    /// either from inside `s` itself or from a subnet linked to it.
+    pub fn reaches(&self, current: &[&Subnet], s: &Subnet) -> bool {
+        current.iter().any(|c| *c == s || c.is_linked_to(s))
+    }
+
+    fn get_gateway(&self, s: &Subnet, g: &str) -> Result<&Host, RoutingError> {
+        self.get_host(g).ok_or_else(|| RoutingError::UnknownGateway(s.subdomain.clone(), g.to_owned()))
+    }
+
+    fn host_route(&self, h: &Host, current: &[&Subnet], entry: Option<&Entry>) -> Result<(Hop, Vec<Hop>), RoutingError> {
+        let h_subnet = self.get_host_subnet(h)?;
+        if self.reaches(current, h_subnet) {
+            debug!("router: client is in or linked to {}'s subnet", h.name);
+            Ok((self.with_options(h, h.get_hop(None)?)?, vec![]))
+        } else if h.is_master() && h_subnet.behind.is_none() {
+            debug!("router: {} is master, connecting directly", h.name);
+            let proxy = h.get_proxy_hop().into_iter().collect();
+            let hop = match entry {
+                Some(e) if e.master == h => e.hop()?,
+                _ => h.get_hop(Some(h_subnet))?,
+            };
+            Ok((self.with_options(h, hop)?, proxy))
+        } else {
+            Ok((self.with_options(h, h.get_hop(None)?)?, self.subnet_route(h_subnet, current, entry)?))
+        }
+    }
+
+    /// Hops needed to get inside `s`, so that its private addresses become reachable.
+    fn subnet_route(&self, s: &Subnet, current: &[&Subnet], entry: Option<&Entry>) -> Result<Vec<Hop>, RoutingError> {
+        if self.reaches(current, s) {
+            return Ok(vec![]);
+        }
+        match &s.behind {
+            None => {
+                debug!("router: entering {} through its master", s.subdomain);
+                let (master, hop) = match entry {
+                    Some(e) => (e.master, e.hop()?),
+                    None => {
+                        let master = s.get_master()?;
+                        (master, master.get_hop(Some(s))?)
+                    }
+                };
+                Ok(master.get_proxy_hop().into_iter().chain([self.with_options(master, hop)?]).collect())
+            }
+            Some(g) => {
+                let gateway = self.get_gateway(s, g)?;
+                debug!("router: {} is behind {}", s.subdomain, gateway.name);
+                let (gateway_hop, mut hops) = self.host_route(gateway, current, entry)?;
+                hops.push(gateway_hop);
+                Ok(hops)
+            }
+        }
+    }
+
+    /// Generates the options reaching the target through `hops`. They are passed with `-J` when
+    /// all of them are plain ssh hops without options; otherwise, since `-J` can't carry options
+    /// or proxies for a single hop, the whole route becomes nested `ProxyCommand`s, the hops
+    /// without options included.
     pub fn gen_ssh_options(hops: Vec<Hop>, port: Option<PortOption>, extra_options: Option<SSHOptionStore>) -> SSHOptionStore {
         debug!("generating ssh options");
         let mut options = SSHOptionStore::default();
@@ -211,7 +764,11 @@ impl NetworkMap {
                 "hops required, adding to options: {:?}",
                 hops.iter().map(|h| h.to_string()).collect::<Vec<String>>()
             );
-            options.add_option(Box::new(JumpHosts::new(hops)));
+            if hops.iter().all(|h| h.is_ssh() && h.options().is_empty()) {
+                options.add_option(Box::new(JumpHosts::new(hops)));
+            } else {
+                options.add_option(Box::new(ProxyCommand::through(&hops)));
+            }
         }
         if let Some(p_o) = port {
             debug!("port specification needed, adding to options: {:?}", p_o);
@@ -241,43 +798,39 @@ impl NetworkMap {
         connection: ConnectionMethod<'_>,
         remote: String,
         mountpoint: String
-    ) -> Box<dyn Process> {
+    ) -> Result<Box<dyn Process>, RoutingError> {
         debug!("generating route to target");
-        let (target_id, route) = match connection {
-            #[cfg(feature = "direct")]
-            ConnectionMethod::Direct(addr) => (Hop::new(target.user.clone(), addr, target.port), vec![]),
-            ConnectionMethod::ViaSubnet(subnet) => self.hops_gen(target, subnet).await,
-        };
+        let (target_id, route) = self.connection_hops(target, connection).await?;
         info!("route generated: {}", join_hops(&target_id, &route, " -> "));
 
-        Box::new(SSHFSProcess::new(
-            target_id.to_string(),
+        Ok(Box::new(SSHFSProcess::new(
+            target_id.to_string_bracketed(),
             remote,
             mountpoint,
             NetworkMap::gen_ssh_options(
                 route,
-                target.port_option(),
-                None
+                target_id.port_option(),
+                target_options(&target_id, None)
             )
-        ))
+        )))
     }
 
     #[cfg(feature = "sync")]
     pub async fn to_ssh_sync(
         &self,
         target: &Host,
-        subnet: Option<&Subnet>,
+        current: &[&Subnet],
         push: bool,
-    ) -> Box<dyn Process> {
+    ) -> Result<Box<dyn Process>, RoutingError> {
         debug!("generating route to target");
-        let (target_id, route) = self.hops_gen(target, subnet).await;
+        let (target_id, route) = self.hops_gen(target, current).await?;
         info!("route generated: {}", join_hops(&target_id, &route, " -> "));
 
         let mut command = vec!["ssh".to_owned()];
         command.append(&mut NetworkMap::gen_ssh_options(
             route,
-            target.port_option(),
-            None
+            target_id.port_option(),
+            target_options(&target_id, None)
         ).args_gen());
         command.push(target_id.to_string());
 
@@ -297,7 +850,7 @@ impl NetworkMap {
 
         debug!("command so far: {:?}", command);
 
-        Box::new(SSHProcess::new(command))
+        Ok(Box::new(SSHProcess::new(command)))
     }
 
     pub async fn to_ssh(
@@ -306,13 +859,9 @@ impl NetworkMap {
         connection: ConnectionMethod<'_>,
         command: &[String],
         extra_options: Option<SSHOptionStore>,
-    ) -> Box<dyn Process> {
+    ) -> Result<Box<dyn Process>, RoutingError> {
         debug!("generating route to target");
-        let (target_id, route) = match connection {
-            #[cfg(feature = "direct")]
-            ConnectionMethod::Direct(addr) => (Hop::new(target.user.clone(), addr, target.port), vec![]),
-            ConnectionMethod::ViaSubnet(subnet) => self.hops_gen(target, subnet).await,
-        };
+        let (target_id, route) = self.connection_hops(target, connection).await?;
 
         info!("route generated: {}", join_hops(&target_id, &route, " -> "));
 
@@ -320,23 +869,19 @@ impl NetworkMap {
         let mut output = extra_options.as_ref().and_then(|v| v.cmd.clone()).unwrap_or(vec!["ssh".to_owned()]);
         output.append(&mut NetworkMap::gen_ssh_options(
             route,
-            target.port_option(),
-            extra_options
+            target_id.port_option(),
+            target_options(&target_id, extra_options)
         ).args_gen());
         output.push(target_id.to_string());
-        output.push(command
-            .iter()
-            .map(|s| if s.contains(' ') { format!("'{}'", s) } else { s.to_owned() })
-            .collect::<Vec<String>>()
-            .join(" ")
-        );
+        // ssh joins the words of the remote command with spaces, for the remote shell
+        output.extend(command.iter().map(|a| shell::quote(a).into_owned()));
         debug!("generated command: {:?}", output);
 
-        Box::new(SSHProcess::new(output))
+        Ok(Box::new(SSHProcess::new(output)))
     }
 
     #[cfg(feature = "wake")]
-    pub async fn wake(&self, target: &Host) -> Result<(), String> {
+    pub async fn wake(&self, target: &Host) -> Result<(), WakeError> {
         match &target.waker {
             None => {
                 info!("won't wake host since it hasn't any waker");
@@ -352,39 +897,42 @@ impl NetworkMap {
                             if res.status() == 200 {
                                 Ok(())
                             } else {
-                                Err(format!("http error: {}", res.status()))
+                                Err(WakeError::Failed(format!("http error: {}", res.status())))
                             }
                         }
-                        Err(e) => Err(format!("request error: {}", e)),
+                        Err(e) => Err(WakeError::Failed(format!("request error: {}", e))),
                     }
                 }
                 Waker::WolWaker { mac } => {
                     info!("waking host with mac {} through ssh", mac);
-                    let master = self.get_host_master(target);
+                    let subnet = self.get_host_subnet(target)?;
+                    let entry = self.select_entry(subnet).await?;
+                    let master = entry.master;
                     info!("master to execute wake on is {}", master.name);
+                    // the entry only applies to top level subnets
+                    let connection = match subnet.behind {
+                        None => ConnectionMethod::ViaMaster(vec![], entry),
+                        Some(_) => ConnectionMethod::ViaSubnet(vec![]),
+                    };
                     debug!("generating ssh command for wake operation");
-                    let mut wake_proc = self
+                    let wake_cmd = self
                         .to_ssh(
                             master,
-                            ConnectionMethod::ViaSubnet(None),
+                            connection,
                             &["wol".to_string(), mac.to_string()],
                             None,
                         )
-                        .await;
-                    debug!("ssh waker command is `{}`", wake_proc.to_string());
-                    match wake_proc.run_stdout_to_stderr() {
-                        Ok(e) => {
-                            if let ExitStatus::Exited(n) = e {
-                                if n == 0 {
-                                    Ok(())
-                                } else {
-                                    Err(format!("ssh waker exited with code {}", n))
-                                }
-                            } else {
-                                Err(format!("ssh waker ended: {:?}", e))
-                            }
-                        }
-                        Err(e) => Err(format!("{:?}", e)),
+                        .await?
+                        .command()
+                        .stdout(Stdout::Stderr);
+                    debug!("ssh waker command is `{}`", wake_cmd);
+                    match Runner::new().run(&wake_cmd).await {
+                        Ok(o) => match o.code {
+                            Some(0) => Ok(()),
+                            Some(n) => Err(WakeError::Failed(format!("ssh waker exited with code {}", n))),
+                            None => Err(WakeError::Failed(format!("ssh waker ended by signal {:?}", o.signal))),
+                        },
+                        Err(e) => Err(WakeError::Failed(format!("{:?}", e))),
                     }
                 }
             },
@@ -426,6 +974,8 @@ pub enum NetworkMapError {
     DuplicateSubnet(String),
     #[cfg(feature = "sync")]
     MultipleSyncHosts(Vec<String>),
+    UnknownLink(String, String),
+    Routing(RoutingError),
 }
 
 impl Display for NetworkMapError {
@@ -436,12 +986,88 @@ impl Display for NetworkMapError {
             NetworkMapError::DuplicateSubnet(s) => write!(f, "duplicate subnet: {}", s),
             #[cfg(feature = "sync")]
             NetworkMapError::MultipleSyncHosts(h) => write!(f, "multiple sync hosts: {}", h.join(", ")),
+            NetworkMapError::UnknownLink(s, l) => write!(f, "subnet {} is linked to unknown subnet: {}", s, l),
+            NetworkMapError::Routing(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RoutingError> for NetworkMapError {
+    fn from(value: RoutingError) -> Self {
+        NetworkMapError::Routing(value)
+    }
+}
+
+#[derive(Debug)]
+pub enum RoutingError {
+    NoMaster(String),
+    NotAMaster(String),
+    NotInSubnet(String),
+    UnknownGateway(String, String),
+    RoutingCycle(Vec<String>),
+    UnknownVia(String, String),
+    UnknownRelay(String, String),
+}
+
+impl Display for RoutingError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RoutingError::NoMaster(s) => write!(f, "subnet {} has no master", s),
+            RoutingError::NotAMaster(h) => write!(f, "cannot generate external hop for non-master host: {}", h),
+            RoutingError::NotInSubnet(h) => write!(f, "host is not in any subnet: {}", h),
+            RoutingError::UnknownGateway(s, h) => write!(f, "subnet {} is behind unknown host: {}", s, h),
+            RoutingError::RoutingCycle(c) => write!(f, "routing cycle: {}", c.join(" -> ")),
+            RoutingError::UnknownVia(h, v) => write!(f, "host {} is routed via unknown host: {}", h, v),
+            RoutingError::UnknownRelay(h, r) => write!(f, "host {} is relayed by unknown host: {}", h, r),
         }
     }
 }
 
+/// Resolves `host` within `limit`, `None` if it has no addresses.
+async fn resolve(host: &str, limit: Duration) -> Option<Vec<IpAddr>> {
+    match timeout(limit, lookup_host((host, 0))).await {
+        Ok(Ok(addrs)) => Some(addrs.map(|a| a.ip()).collect::<Vec<_>>()).filter(|a| !a.is_empty()),
+        _ => None,
+    }
+}
+
+/// Store with the ssh options of the `target` hop, overridden by the `extra` ones.
+fn target_options(target: &Hop, extra: Option<SSHOptionStore>) -> Option<SSHOptionStore> {
+    if target.options().is_empty() {
+        return extra;
+    }
+    let mut options = SSHOptionStore::default();
+    for (k, v) in target.options() {
+        options.set_config_option(k.clone(), v.clone());
+    }
+    if let Some(e) = extra {
+        options.merge(e);
+    }
+    Some(options)
+}
+
+#[cfg_attr(not(feature = "log"), allow(unused_variables))]
+fn selected<'a>(s: &Subnet, entry: Entry<'a>) -> Entry<'a> {
+    info!("router: selected master {} through {} for subnet {}", entry.master.name, entry.endpoint.host, s.subdomain);
+    entry
+}
+
+/// Index of the fastest reachable probe, if any.
+fn fastest(probes: &[Probe]) -> Option<usize> {
+    probes
+        .iter()
+        .enumerate()
+        .filter_map(|(i, p)| p.latency.map(|l| (i, l)))
+        .min_by_key(|(_, l)| *l)
+        .map(|(i, _)| i)
+}
+
 pub enum ConnectionMethod<'a> {
-    ViaSubnet(Option<&'a Subnet>),
+    /// Routes from the subnets the client is currently in, no jump is needed to enter them.
+    ViaSubnet(Vec<&'a Subnet>),
+    /// Like `ViaSubnet`, but the top level subnet of the target is entered through the given
+    /// master and endpoint.
+    ViaMaster(Vec<&'a Subnet>, Entry<'a>),
     #[cfg(feature = "direct")]
     Direct(String),
 }
@@ -0,0 +1,78 @@
+use std::fmt::{Display, Formatter};
+use std::net::{Ipv6Addr, SocketAddr};
+use std::time::{Duration, Instant};
+use futures::stream::{FuturesUnordered, StreamExt};
+use tokio::net::TcpStream;
+use tokio::time::timeout;
+
+pub const PROBE_TIMEOUT: Duration = Duration::from_millis(2000);
+
+/// Outcome of a latency probe against a candidate first hop.
+#[derive(Debug, Clone)]
+pub struct Probe {
+    pub target: String,
+    pub latency: Option<Duration>,
+}
+
+impl Probe {
+    /// Measures the time needed to open a tcp connection to `host:port`.
+    pub async fn tcp(host: &str, port: u16, limit: Duration) -> Probe {
+        let start = Instant::now();
+        let latency = match timeout(limit, TcpStream::connect((host, port))).await {
+            Ok(Ok(_)) => Some(start.elapsed()),
+            _ => None,
+        };
+        let target = match host.parse::<Ipv6Addr>() {
+            Ok(ip) => SocketAddr::from((ip, port)).to_string(),
+            Err(_) => format!("{}:{}", host, port),
+        };
+        Probe { target, latency }
+    }
+}
+
+/// Probes all `targets` concurrently and returns the index of the first reachable one, in order
+/// of preference. The answer is returned as soon as it is known: when a target answers and all
+/// the preferred ones have failed, without waiting for the less preferred ones.
+pub async fn first_reachable(targets: &[(String, u16)], limit: Duration) -> Option<usize> {
+    let mut pending: FuturesUnordered<_> = targets
+        .iter()
+        .enumerate()
+        .map(|(i, (host, port))| async move { (i, Probe::tcp(host, *port, limit).await) })
+        .collect();
+    let mut outcomes: Vec<Option<bool>> = vec![None; targets.len()];
+    while let Some((i, probe)) = pending.next().await {
+        outcomes[i] = Some(probe.latency.is_some());
+        for (j, outcome) in outcomes.iter().enumerate() {
+            match outcome {
+                Some(true) => return Some(j),
+                Some(false) => continue,
+                // a preferred target is still being probed
+                None => break,
+            }
+        }
+    }
+    None
+}
+
+/// Probes all `targets` concurrently and returns as soon as any of them answers.
+pub async fn any_reachable(targets: &[(String, u16)], limit: Duration) -> bool {
+    let mut pending: FuturesUnordered<_> = targets
+        .iter()
+        .map(|(host, port)| Probe::tcp(host, *port, limit))
+        .collect();
+    while let Some(probe) = pending.next().await {
+        if probe.latency.is_some() {
+            return true;
+        }
+    }
+    false
+}
+
+impl Display for Probe {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self.latency {
+            Some(l) => write!(f, "{}: {:.1} ms", self.target, l.as_secs_f64() * 1000.0),
+            None => write!(f, "{}: unreachable", self.target),
+        }
+    }
+}
@@ -2,10 +2,14 @@
 
 #[cfg(feature = "serde")]
 use serde::{Deserialize,Serialize,Serializer,Deserializer};
+use std::collections::BTreeMap;
 use std::fmt::{Debug, Formatter};
 use std::net::IpAddr;
+use ipnet::{IpNet, Ipv6Net};
 
-use crate::net::Host;
+use crate::net::{Host, RoutingError};
+use crate::ssh::hop::Hop;
+use crate::net::local::{normalize_mac, LocalNetwork};
 
 #[cfg_attr(feature = "serde", derive(Deserialize,Serialize))]
 pub struct Subnet {
@@ -19,6 +23,77 @@ pub struct Subnet {
         skip_serializing_if = "Option::is_none",
     ))]
     pub eip: Option<IpAddr>,
+    // global IPv6 prefix of the subnet: there is no NAT with IPv6, so the external address of a
+    // client inside the subnet is its own global address, belonging to this prefix
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub eip6: Option<Ipv6Net>,
+    // external endpoints through which the masters are reached, in order of preference: when
+    // empty the subdomain is the only endpoint
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub endpoints: Vec<Endpoint>,
+    // ssh_config options (e.g. `ServerAliveInterval`) of every host of the subnet
+    #[cfg_attr(
+        feature = "serde",
+        serde(default, skip_serializing_if = "BTreeMap::is_empty", deserialize_with = "crate::net::host::deserialize_ssh_options"),
+    )]
+    pub ssh_options: BTreeMap<String, String>,
+    // subdomains of the subnets whose private addresses are reachable from inside this one and
+    // vice versa, e.g. through a site-to-site VPN
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub links: Vec<String>,
+    // if this is not None the subnet is only reachable through the named host of another subnet
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub behind: Option<String>,
+    // local address ranges of the subnet, used to recognize it from the interfaces addresses
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Vec::is_empty"))]
+    pub cidrs: Vec<IpNet>,
+    // default gateway of the subnet, narrows down matches on `cidrs`
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub gateway: Option<IpAddr>,
+    // hardware address of the default gateway, required to trust ip based matches
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub gateway_mac: Option<String>,
+}
+
+/// An external address of a subnet, e.g. one of its internet connections.
+#[cfg_attr(feature = "serde", derive(Deserialize,Serialize))]
+#[derive(Debug, Clone, PartialEq)]
+pub struct Endpoint {
+    pub host: String,
+    // overrides the `eport` of the masters
+    #[cfg_attr(feature = "serde", serde(default, skip_serializing_if = "Option::is_none"))]
+    pub port: Option<u16>,
+}
+
+impl Endpoint {
+    pub fn new(host: String, port: Option<u16>) -> Endpoint {
+        Endpoint { host, port }
+    }
+}
+
+/// Master and endpoint through which a subnet is entered from outside.
+#[derive(Debug, Clone)]
+pub struct Entry<'a> {
+    pub master: &'a Host,
+    pub endpoint: Endpoint,
+}
+
+impl Entry<'_> {
+    pub fn hop(&self) -> Result<Hop, RoutingError> {
+        self.master.get_external_hop(&self.endpoint)
+    }
+
+    /// Host and port to connect to.
+    pub fn address(&self) -> (String, u16) {
+        let port = self.endpoint.port.or(self.master.eport).unwrap_or(self.master.port);
+        (self.endpoint.host.clone(), port)
+    }
+
+    /// Whether the master is only reachable through its proxy, so that it can't be probed with a
+    /// direct connection.
+    pub fn is_proxied(&self) -> bool {
+        self.master.proxy.is_some()
+    }
 }
 
 fn get_none() -> Option<IpAddr> {
@@ -30,7 +105,15 @@ impl Subnet {
         Subnet {
             subdomain,
             eip,
+            eip6: None,
+            endpoints: Vec::new(),
+            links: Vec::new(),
+            ssh_options: BTreeMap::new(),
             hosts: Vec::new(),
+            behind: None,
+            cidrs: Vec::new(),
+            gateway: None,
+            gateway_mac: None,
         }
     }
 
@@ -42,14 +125,92 @@ impl Subnet {
         self.hosts.iter().find(|h| h.name == q || h.aliases.contains(q))
     }
 
+    pub fn get_host_mut(&mut self, q: &str) -> Option<&mut Host> {
+        self.hosts.iter_mut().find(|h| h.name == q || h.aliases.contains(q))
+    }
+
     pub fn get_hosts(&self) -> Vec<&Host> {
         self.hosts.iter().collect()
     }
 
-    pub fn get_master(&self) -> &Host {
-        match self.hosts.iter().find(|h| h.is_master()) {
-            Some(h) => h,
-            None => panic!("subnet {} has no master", self.subdomain),
+    /// Gets the masters of this subnet, sorted by priority. Masters without a priority come last,
+    /// in the order they were declared.
+    pub fn get_masters(&self) -> Vec<&Host> {
+        let mut masters: Vec<&Host> = self.hosts.iter().filter(|h| h.is_master()).collect();
+        masters.sort_by_key(|h| h.priority.unwrap_or(u16::MAX));
+        masters
+    }
+
+    /// Gets the preferred master of this subnet.
+    pub fn get_master(&self) -> Result<&Host, RoutingError> {
+        self.get_masters()
+            .first()
+            .copied()
+            .ok_or_else(|| RoutingError::NoMaster(self.subdomain.clone()))
+    }
+
+    /// Gets the external endpoints of this subnet, in order of preference.
+    pub fn get_endpoints(&self) -> Vec<Endpoint> {
+        if self.endpoints.is_empty() {
+            vec![Endpoint::new(self.subdomain.clone(), None)]
+        } else {
+            self.endpoints.clone()
+        }
+    }
+
+    /// Gets the hosts of the external endpoints of this subnet.
+    pub fn endpoint_hosts(&self) -> Vec<&str> {
+        if self.endpoints.is_empty() {
+            vec![self.subdomain.as_str()]
+        } else {
+            self.endpoints.iter().map(|e| e.host.as_str()).collect()
+        }
+    }
+
+    /// Gets the preferred external endpoint of this subnet.
+    pub fn get_endpoint(&self) -> Endpoint {
+        self.get_endpoints().swap_remove(0)
+    }
+
+    /// Gets every way to enter this subnet from outside: each master, by priority, through each
+    /// endpoint, in order.
+    pub fn get_entries(&self) -> Vec<Entry<'_>> {
+        let endpoints = self.get_endpoints();
+        self.get_masters()
+            .into_iter()
+            .flat_map(|master| endpoints.iter().map(move |e| Entry { master, endpoint: e.clone() }))
+            .collect()
+    }
+
+    /// Checks whether this subnet and `other` are linked, in either direction.
+    pub fn is_linked_to(&self, other: &Subnet) -> bool {
+        self.links.contains(&other.subdomain) || other.links.contains(&self.subdomain)
+    }
+
+    /// Checks whether the local interface addresses and default gateways belong to this subnet.
+    /// Subnets without `cidrs` never match.
+    pub fn matches_local(&self, local: &LocalNetwork) -> bool {
+        local.addrs.iter().any(|a| self.cidrs.iter().any(|c| c.contains(a)))
+            && self.gateway.is_none_or(|g| local.gateways.contains(&g))
+            && self.matches_gateway_mac(local)
+    }
+
+    /// Checks the pinned `gateway_mac`, if any, against the macs of the local default gateways.
+    pub fn matches_gateway_mac(&self, local: &LocalNetwork) -> bool {
+        self.gateway_mac
+            .as_ref()
+            .is_none_or(|m| local.gateway_macs.contains(&normalize_mac(m)))
+    }
+
+    /// Checks whether `ip`, the external address of the client, belongs to this subnet. When the
+    /// subnet has no `eip`, `resolved` (the addresses of its endpoints) are used instead.
+    pub fn matches_external_ip(&self, ip: IpAddr, resolved: &[IpAddr]) -> bool {
+        match ip {
+            IpAddr::V6(v6) if self.eip6.is_some_and(|p| p.contains(&v6)) => true,
+            _ => match self.eip {
+                Some(eip) => eip == ip,
+                None => resolved.contains(&ip),
+            },
         }
     }
 
@@ -67,9 +228,17 @@ impl Debug for Subnet {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
         write!(
             f,
-            "Subnet {{ subdomain: \"{}\", eip: {:?}, hosts: {} }}",
+            "Subnet {{ subdomain: \"{}\", eip: {:?}, eip6: {:?}, endpoints: {:?}, links: {:?}, ssh_options: {:?}, behind: {:?}, cidrs: {:?}, gateway: {:?}, gateway_mac: {:?}, hosts: {} }}",
             self.subdomain,
             self.eip,
+            self.eip6,
+            self.endpoints,
+            self.links,
+            self.ssh_options,
+            self.behind,
+            self.cidrs,
+            self.gateway,
+            self.gateway_mac,
             self.hosts.len()
         )
     }
@@ -1,31 +1,130 @@
+use std::collections::BTreeMap;
 use std::fmt::{Display, Formatter};
+use std::net::Ipv6Addr;
+
+use crate::ssh::options::PortOption;
+
+/// How a hop is traversed.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum HopKind {
+    /// An ssh server allowing tcp forwarding, chained with `-J`.
+    #[default]
+    Ssh,
+    /// A SOCKS5 proxy.
+    Socks5,
+    /// An HTTP proxy supporting the CONNECT method.
+    HttpConnect,
+    /// A command connecting its stdin and stdout to the next hop, as an ssh `ProxyCommand`.
+    /// `%h` and `%p` are replaced by the host and port of the next hop.
+    Command(String),
+}
 
 #[derive(Debug)]
 pub struct Hop {
     user: String,
     host: String,
     port: u16,
+    kind: HopKind,
+    // ssh_config options used when connecting to this hop
+    options: BTreeMap<String, String>,
 }
 
 impl Hop {
     pub fn new(user: String, host: String, port: u16) -> Hop {
-        Hop { user, host, port }
+        Hop { user, host, port, kind: HopKind::Ssh, options: BTreeMap::new() }
+    }
+
+    pub fn socks5(host: String, port: u16) -> Hop {
+        Hop { user: String::new(), host, port, kind: HopKind::Socks5, options: BTreeMap::new() }
+    }
+
+    pub fn http_connect(host: String, port: u16) -> Hop {
+        Hop { user: String::new(), host, port, kind: HopKind::HttpConnect, options: BTreeMap::new() }
+    }
+
+    pub fn command(command: String) -> Hop {
+        Hop { user: String::new(), host: String::new(), port: 0, kind: HopKind::Command(command), options: BTreeMap::new() }
+    }
+
+    pub fn with_options(mut self, options: BTreeMap<String, String>) -> Hop {
+        self.options = options;
+        self
+    }
+
+    pub fn options(&self) -> &BTreeMap<String, String> {
+        &self.options
+    }
+
+    pub fn kind(&self) -> &HopKind {
+        &self.kind
+    }
+
+    pub fn is_ssh(&self) -> bool {
+        self.kind == HopKind::Ssh
+    }
+
+    pub fn user(&self) -> &str {
+        &self.user
+    }
+
+    pub fn host(&self) -> &str {
+        &self.host
+    }
+
+    pub fn port(&self) -> u16 {
+        self.port
+    }
+
+    /// Port to pass with `-p`, if not the default one.
+    pub fn port_option(&self) -> Option<PortOption> {
+        if self.port == 22 {
+            None
+        } else {
+            Some(PortOption::new(self.port))
+        }
+    }
+
+    /// Like the `Display` impl, but IPv6 addresses are enclosed in brackets, as required by
+    /// `-J` and by `host:path` arguments.
+    pub fn to_string_bracketed(&self) -> String {
+        if self.host.parse::<Ipv6Addr>().is_ok() {
+            format!("{}@[{}]", self.user, self.host)
+        } else {
+            self.to_string()
+        }
     }
 
     pub fn to_string_with_port(&self) -> String {
-        format!("{}{}",
-                self,
+        match &self.kind {
+            HopKind::Ssh => format!("{}{}",
+                self.to_string_bracketed(),
                 if self.port == 22 {
                     "".to_string()
                 } else {
                     format!(":{}", self.port)
-                })
+                }),
+            _ => self.to_string(),
+        }
+    }
+
+    /// Host and port of the hop as `host:port`, with IPv6 addresses enclosed in brackets.
+    pub fn address(&self) -> String {
+        if self.host.parse::<Ipv6Addr>().is_ok() {
+            format!("[{}]:{}", self.host, self.port)
+        } else {
+            format!("{}:{}", self.host, self.port)
+        }
     }
 }
 
 impl Display for Hop {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}@{}", self.user, self.host)
+        match &self.kind {
+            HopKind::Ssh => write!(f, "{}@{}", self.user, self.host),
+            HopKind::Socks5 => write!(f, "socks5://{}", self.address()),
+            HopKind::HttpConnect => write!(f, "http://{}", self.address()),
+            HopKind::Command(c) => write!(f, "`{}`", c),
+        }
     }
 }
 
@@ -0,0 +1,54 @@
+//! Retrieval of ssh host keys, used to make sure a host is really the one we think it is.
+#![cfg_attr(not(feature = "log"), allow(unused_variables))]
+#[cfg(not(feature = "log"))]
+use crate::debug;
+#[cfg(feature = "log")]
+use log::debug;
+use std::time::Duration;
+
+use crate::ssh::process::Command;
+use crate::ssh::runner::Runner;
+
+/// Fetches the host keys of `host:port` with `ssh-keyscan` and returns their fingerprints, as
+/// printed by `ssh-keygen -l`. The scan is given at most `limit`, any failure results in an
+/// empty list.
+pub async fn host_key_fingerprints(host: &str, port: u16, limit: Duration) -> Vec<String> {
+    // ssh-keyscan only takes whole seconds
+    let secs = limit.as_secs() + (limit.subsec_nanos() > 0) as u64;
+    let keyscan = Command::new("ssh-keyscan").args(["-T", &secs.max(1).to_string(), "-p", &port.to_string(), host]);
+    let runner = Runner::new().capture(true).timeout(limit).grace(Duration::from_millis(100));
+    let keys = match runner.run(&keyscan).await {
+        Ok(o) if !o.stdout_str().trim().is_empty() => o.stdout_str(),
+        Ok(o) => {
+            debug!("no host keys scanned from {}:{} ({:?})", host, port, o.termination);
+            return vec![];
+        }
+        Err(e) => {
+            debug!("cannot scan host keys of {}:{}: {}", host, port, e);
+            return vec![];
+        }
+    };
+    let keygen = Command::new("ssh-keygen").args(["-l", "-f", "-"]);
+    match runner.input(keys).run(&keygen).await {
+        Ok(o) => parse_fingerprints(&o.stdout_str()),
+        Err(e) => {
+            debug!("cannot compute the fingerprints of {}:{}: {}", host, port, e);
+            vec![]
+        }
+    }
+}
+
+/// Extracts the fingerprints from the output of `ssh-keygen -l`.
+pub fn parse_fingerprints(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .filter_map(|l| l.split_whitespace().nth(1))
+        .map(|f| f.to_owned())
+        .collect()
+}
+
+/// Compares two fingerprints, the `SHA256:` prefix being optional.
+pub fn same_fingerprint(a: &str, b: &str) -> bool {
+    let strip = |f: &str| f.trim().strip_prefix("SHA256:").unwrap_or(f.trim()).to_owned();
+    strip(a) == strip(b)
+}
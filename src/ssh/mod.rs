@@ -1,10 +1,13 @@
 pub mod hop;
+pub mod keyscan;
 mod options_internal;
 pub mod process;
+pub mod runner;
+pub mod shell;
 
 pub mod options {
     use crate::ssh::options_internal;
-    pub use options_internal::{GenericOption, JumpHosts, PortOption};
+    pub use options_internal::{ConfigOption, GenericOption, JumpHosts, PortOption, ProxyCommand, SSHOption};
 }
 
 pub use hop::Hop;
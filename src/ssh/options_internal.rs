@@ -1,36 +1,53 @@
 #![allow(dead_code)]
 
-use std::collections::BTreeMap;
-use crate::ssh::hop::Hop;
+use std::borrow::Cow;
+use std::collections::HashSet;
+use std::net::Ipv6Addr;
+use crate::ssh::hop::{Hop, HopKind};
+use crate::ssh::shell::{self, quote};
 
 pub trait SSHOption {
     fn extended_name(&self) -> bool;
-    fn name(&self) -> &'static str;
+    fn name(&self) -> &str;
     fn value(&self) -> Option<String>;
+
+    /// Identifies the option in a [`SSHOptionStore`]: an option replaces the one with the same
+    /// key. Options that can be repeated (e.g. `-L`) include their value in the key.
+    fn key(&self) -> String {
+        self.name().to_owned()
+    }
 }
 
+/// Options given to ssh, in insertion order.
 #[derive(Default)]
 pub struct SSHOptionStore {
-    options: BTreeMap<&'static str, Box<dyn SSHOption>>,
+    options: Vec<Box<dyn SSHOption>>,
     pub cmd: Option<Vec<String>>
 }
 
 impl SSHOptionStore {
+    /// Creates a store running `cmd` instead of `ssh`, split into words as a shell would (or
+    /// at whitespace, if its quotes are unbalanced).
     pub fn new(cmd: Option<String>) -> Self {
         Self {
-            cmd: cmd.map(|f| 
-                     f.split(' ')
-                     .map(|s| s.to_string())
-                     .collect::<Vec<String>>()
-            ),
-            options: BTreeMap::new(),
+            cmd: cmd.map(|f| match shell::split(&f) {
+                Ok(words) => words,
+                Err(_) => f.split_whitespace().map(str::to_owned).collect(),
+            }),
+            options: Vec::new(),
         }
     }
 
+    /// Generates the arguments, in insertion order. Identical arguments are emitted once, and
+    /// adjacent switches are coupled (e.g. `-tv`).
     pub fn args_gen(&self) -> Vec<String> {
         let mut out = Vec::new();
+        let mut seen = HashSet::new();
         let mut coupling = false;
-        for o in self.options.values() {
+        for o in self.options.iter() {
+            if !seen.insert((o.extended_name(), o.name().to_owned(), o.value())) {
+                continue;
+            }
             if o.extended_name() {
                 out.push(format!("--{}", o.name()));
                 if let Some(v) = o.value() {
@@ -56,17 +73,67 @@ impl SSHOptionStore {
         out
     }
 
+    /// Adds `option`, replacing in place the one with the same [key](SSHOption::key), if any.
     pub fn add_option(&mut self, option: Box<dyn SSHOption>) {
-        self.options.insert(option.name(), option);
+        let key = option.key();
+        match self.options.iter_mut().find(|o| o.key() == key) {
+            Some(o) => *o = option,
+            None => self.options.push(option),
+        }
+    }
+
+    /// Sets an ssh_config option, passed with `-o Key=Value`.
+    pub fn set_config_option(&mut self, key: String, value: String) {
+        self.add_option(Box::new(ConfigOption::new(key, value)))
+    }
+
+    pub fn get_option(&self, key: &str) -> Option<&dyn SSHOption> {
+        self.options.iter().find(|o| o.key() == key).map(|o| o.as_ref())
     }
 
+    /// Adds the options of `other`, that take precedence over the ones already present.
     pub fn merge(&mut self, other: Self) {
-        for o in other.options.into_values() {
+        for o in other.options {
             self.add_option(o)
         }
     }
 }
 
+/// An ssh_config option, passed with `-o Key=Value`. Keys are case insensitive, as in ssh.
+#[derive(Debug, Clone, PartialEq)]
+pub struct ConfigOption {
+    key: String,
+    value: String,
+}
+
+impl ConfigOption {
+    pub fn new(key: String, value: String) -> ConfigOption {
+        ConfigOption { key, value }
+    }
+}
+
+impl SSHOption for ConfigOption {
+    fn extended_name(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &str {
+        "o"
+    }
+
+    fn value(&self) -> Option<String> {
+        Some(format!("{}={}", self.key, self.value))
+    }
+
+    fn key(&self) -> String {
+        config_key(&self.key)
+    }
+}
+
+fn config_key(key: &str) -> String {
+    format!("o:{}", key.to_lowercase())
+}
+
 pub struct JumpHosts {
     hops: Vec<Hop>,
 }
@@ -86,7 +153,7 @@ impl SSHOption for JumpHosts {
         false
     }
 
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "J"
     }
 
@@ -96,14 +163,88 @@ impl SSHOption for JumpHosts {
                 .iter()
                 .map(|h| h.to_string_with_port())
                 .collect::<Vec<String>>()
-                .join("."),
+                .join(","),
         )
     }
 }
 
+/// `ProxyCommand` reaching the destination through hops that `-J` cannot chain, because some of
+/// them are proxies rather than ssh servers. A proxy is used from the closest ssh hop before it,
+/// or from the client when there is none.
+pub struct ProxyCommand {
+    command: String,
+}
+
+impl ProxyCommand {
+    pub fn new(command: String) -> ProxyCommand {
+        ProxyCommand { command }
+    }
+
+    pub fn through(hops: &[Hop]) -> ProxyCommand {
+        ProxyCommand { command: reach(hops, "%h", "%p") }
+    }
+}
+
+impl SSHOption for ProxyCommand {
+    fn extended_name(&self) -> bool {
+        false
+    }
+
+    fn name(&self) -> &str {
+        "o"
+    }
+
+    fn value(&self) -> Option<String> {
+        Some(format!("ProxyCommand={}", self.command))
+    }
+
+    fn key(&self) -> String {
+        config_key("ProxyCommand")
+    }
+}
+
+/// Command connecting its stdin and stdout to `host:port` through `hops`, in order.
+fn reach(hops: &[Hop], host: &str, port: &str) -> String {
+    let Some((last, before)) = hops.split_last() else {
+        return format!("nc {} {}", host, port);
+    };
+    let connect = match last.kind() {
+        HopKind::Ssh => {
+            let host = if host.parse::<Ipv6Addr>().is_ok() { format!("[{}]", host) } else { host.to_owned() };
+            return ssh_command(before, last, &format!("-W {}:{}", host, port), "");
+        }
+        HopKind::Socks5 => format!("nc -X 5 -x {} {} {}", last.address(), host, port),
+        HopKind::HttpConnect => format!("nc -X connect -x {} {} {}", last.address(), host, port),
+        HopKind::Command(c) => c.replace("%h", host).replace("%p", port),
+    };
+    match before.iter().rposition(Hop::is_ssh) {
+        Some(i) => ssh_command(&before[..i], &before[i], "", &connect),
+        None => connect,
+    }
+}
+
+/// Command running ssh to `hop`, reached through `before`.
+fn ssh_command(before: &[Hop], hop: &Hop, args: &str, remote: &str) -> String {
+    let mut command = vec!["ssh".to_owned()];
+    if !before.is_empty() {
+        let proxy = reach(before, hop.host(), &hop.port().to_string());
+        command.push(format!("-o {}", quote(&format!("ProxyCommand={}", proxy))));
+    }
+    if hop.port() != 22 {
+        command.push(format!("-p {}", hop.port()));
+    }
+    for (k, v) in hop.options() {
+        command.push(format!("-o {}", quote(&format!("{}={}", k, v))));
+    }
+    command.extend([args.to_owned(), hop.to_string(), remote.to_owned()]);
+    command.retain(|a| !a.is_empty());
+    command.join(" ")
+}
+
+/// An option given by its name, e.g. `t` for `-t` or `escape` for `--escape`.
 pub enum GenericOption {
-    Switch(&'static str),
-    Value(&'static str, String),
+    Switch(Cow<'static, str>),
+    Value(Cow<'static, str>, String),
 }
 
 impl SSHOption for GenericOption {
@@ -114,7 +255,7 @@ impl SSHOption for GenericOption {
         }.len() > 1
     }
 
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         match self {
             Self::Switch(s) => s,
             Self::Value(s, _) => s,
@@ -127,8 +268,19 @@ impl SSHOption for GenericOption {
             Self::Value(_, s) => Some(s.clone()),
         }
     }
+
+    fn key(&self) -> String {
+        match self {
+            Self::Value(n, v) if n == "o" => config_key(v.split_once('=').map_or(v.as_str(), |(k, _)| k)),
+            Self::Value(n, v) if REPEATABLE.contains(&n.as_ref()) => format!("{}:{}", n, v),
+            _ => self.name().to_owned(),
+        }
+    }
 }
 
+/// Options that ssh accepts several times.
+const REPEATABLE: [&str; 4] = ["D", "i", "L", "R"];
+
 #[derive(Debug)]
 pub struct PortOption {
     port: u16,
@@ -145,7 +297,7 @@ impl SSHOption for PortOption {
         false
     }
 
-    fn name(&self) -> &'static str {
+    fn name(&self) -> &str {
         "p"
     }
 
@@ -5,22 +5,170 @@ use crate::debug;
 #[cfg(feature = "log")]
 use log::debug;
 use std::fmt::{Display, Formatter};
+use std::fs::File;
+use std::path::PathBuf;
 
-use subprocess::{Exec, ExitStatus, PopenError, Redirection};
+use subprocess::{Exec, ExitStatus, NullFile, PopenError, Redirection};
+
+use crate::ssh::shell;
+
+/// Where the standard input of a [`Command`] comes from.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Stdin {
+    #[default]
+    Inherit,
+    Null,
+    File(PathBuf),
+}
+
+/// Where the standard output of a [`Command`] goes.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub enum Stdout {
+    #[default]
+    Inherit,
+    Null,
+    /// Merged into the standard error.
+    Stderr,
+    /// Captured by the caller.
+    Pipe,
+    File(PathBuf),
+}
+
+/// A program with its arguments, environment and redirections.
+///
+/// It is displayed as the equivalent POSIX shell command, and [`Command::exec`] runs exactly
+/// that command.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Command {
+    program: String,
+    args: Vec<String>,
+    env: Vec<(String, String)>,
+    stdin: Stdin,
+    stdout: Stdout,
+}
+
+impl Command {
+    pub fn new<S: Into<String>>(program: S) -> Command {
+        Command {
+            program: program.into(),
+            args: vec![],
+            env: vec![],
+            stdin: Stdin::default(),
+            stdout: Stdout::default(),
+        }
+    }
+
+    /// Builds a command from its argv, the first word being the program.
+    pub fn from_argv(argv: Vec<String>) -> Option<Command> {
+        let mut argv = argv.into_iter();
+        argv.next().map(|p| Command::new(p).args(argv))
+    }
+
+    pub fn arg<S: Into<String>>(mut self, arg: S) -> Command {
+        self.args.push(arg.into());
+        self
+    }
+
+    pub fn args<I: IntoIterator<Item = S>, S: Into<String>>(mut self, args: I) -> Command {
+        self.args.extend(args.into_iter().map(Into::into));
+        self
+    }
+
+    pub fn env<K: Into<String>, V: Into<String>>(mut self, key: K, value: V) -> Command {
+        self.env.push((key.into(), value.into()));
+        self
+    }
+
+    pub fn stdin(mut self, stdin: Stdin) -> Command {
+        self.stdin = stdin;
+        self
+    }
+
+    pub fn stdout(mut self, stdout: Stdout) -> Command {
+        self.stdout = stdout;
+        self
+    }
+
+    pub fn get_program(&self) -> &str {
+        &self.program
+    }
+
+    pub fn get_args(&self) -> &[String] {
+        &self.args
+    }
+
+    pub fn get_env(&self) -> &[(String, String)] {
+        &self.env
+    }
+
+    pub fn get_stdin(&self) -> &Stdin {
+        &self.stdin
+    }
+
+    pub fn get_stdout(&self) -> &Stdout {
+        &self.stdout
+    }
+
+    /// The program followed by its arguments.
+    pub fn argv(&self) -> Vec<String> {
+        std::iter::once(self.program.clone()).chain(self.args.iter().cloned()).collect()
+    }
+
+    pub fn exec(&self) -> Result<Exec, PopenError> {
+        let mut exec = Exec::cmd(&self.program).args(self.args.as_slice());
+        for (k, v) in &self.env {
+            exec = exec.env(k, v);
+        }
+        exec = match &self.stdin {
+            Stdin::Inherit => exec,
+            Stdin::Null => exec.stdin(NullFile),
+            Stdin::File(p) => exec.stdin(File::open(p)?),
+        };
+        Ok(match &self.stdout {
+            Stdout::Inherit => exec,
+            Stdout::Null => exec.stdout(NullFile),
+            Stdout::Stderr => exec.stdout(Redirection::Merge),
+            Stdout::Pipe => exec.stdout(Redirection::Pipe),
+            Stdout::File(p) => exec.stdout(File::create(p)?),
+        })
+    }
+}
+
+impl Display for Command {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        for (k, v) in &self.env {
+            write!(f, "{}={} ", k, shell::quote(v))?;
+        }
+        write!(f, "{}", shell::join(&self.argv()))?;
+        match &self.stdin {
+            Stdin::Inherit => {}
+            Stdin::Null => write!(f, " < /dev/null")?,
+            Stdin::File(p) => write!(f, " < {}", shell::quote(&p.to_string_lossy()))?,
+        }
+        match &self.stdout {
+            // a piped output is read by the caller, there is no shell equivalent
+            Stdout::Inherit | Stdout::Pipe => Ok(()),
+            Stdout::Null => write!(f, " > /dev/null"),
+            Stdout::Stderr => write!(f, " >&2"),
+            Stdout::File(p) => write!(f, " > {}", shell::quote(&p.to_string_lossy())),
+        }
+    }
+}
 
 pub trait Process {
-    fn get_args(&self) -> Vec<String>;
+    fn command(&self) -> Command;
+
+    fn get_args(&self) -> Vec<String> {
+        self.command().argv()
+    }
 
-    fn exec(&self) -> Exec {
-        let args = self.get_args();
-        Exec::cmd("ssh")
-            // skip first argument of args if it's not empty
-            .args(if args.is_empty() { &[] } else { &args[1..] })
+    fn exec(&self) -> Result<Exec, PopenError> {
+        self.command().exec()
     }
-    
+
     fn run(&mut self) -> Result<ExitStatus, PopenError> {
         debug!("spawning new process");
-        let exec = self.exec();
+        let exec = self.exec()?;
         self.inner_run(exec)
     }
 
@@ -36,31 +184,37 @@ pub trait Process {
     }
 
     fn run_stdout_to_stderr(&mut self) -> Result<ExitStatus, PopenError> {
-        debug!("passing redirecting options to ssh process");
-        let exec = self.exec()
-            .stdout(Redirection::Merge);
+        debug!("redirecting the process output to stderr");
+        let exec = self.command().stdout(Stdout::Stderr).exec()?;
         self.inner_run(exec)
     }
 }
 
 pub struct SSHProcess {
-    args: Vec<String>,
+    command: Command,
 }
 
 impl SSHProcess {
+    /// Creates the process from its argv, defaulting to a bare `ssh` when it's empty.
     pub fn new(args: Vec<String>) -> SSHProcess {
-        SSHProcess { args }
+        SSHProcess { command: Command::from_argv(args).unwrap_or(Command::new("ssh")) }
+    }
+}
+
+impl From<Command> for SSHProcess {
+    fn from(command: Command) -> Self {
+        SSHProcess { command }
     }
 }
 
 impl Process for SSHProcess {
-    fn get_args(&self) -> Vec<String> {
-        self.args.clone()
+    fn command(&self) -> Command {
+        self.command.clone()
     }
 }
 
 impl Display for dyn Process {
     fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
-        write!(f, "{}", self.get_args().join(" "))
+        write!(f, "{}", self.command())
     }
 }
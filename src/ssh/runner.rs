@@ -0,0 +1,284 @@
+//! Asynchronous execution of a [`Command`] on the tokio runtime.
+//!
+//! A [`Runner`] can stop the command after a timeout, forward Ctrl-C to it instead of dying
+//! with it, and capture its output. Dropping the future returned by [`Runner::run`] kills the
+//! command.
+#![cfg_attr(not(feature = "log"), allow(unused_variables))]
+#[cfg(not(feature = "log"))]
+use crate::{debug, warn};
+#[cfg(feature = "log")]
+use log::{debug, warn};
+use std::io;
+use std::process::{ExitStatus, Stdio};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWriteExt};
+use tokio::process::Child;
+use tokio::task::JoinHandle;
+use tokio::time::timeout;
+
+use crate::ssh::process::{Command, Stdin, Stdout};
+
+/// How long a command is given to exit after being interrupted or timing out, before being
+/// killed.
+pub const KILL_GRACE: Duration = Duration::from_secs(5);
+
+#[derive(Debug, Clone)]
+pub struct Runner {
+    timeout: Option<Duration>,
+    grace: Duration,
+    capture: bool,
+    forward_interrupt: bool,
+    input: Option<Vec<u8>>,
+}
+
+/// Why the command ended.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Termination {
+    /// The command exited on its own.
+    Exited,
+    /// The command was stopped because it ran past the timeout.
+    TimedOut,
+    /// The command was stopped by a forwarded Ctrl-C.
+    Interrupted,
+}
+
+#[derive(Debug)]
+pub struct RunOutput {
+    /// Exit code, missing when the command was killed by a signal.
+    pub code: Option<i32>,
+    /// Signal that killed the command, if any.
+    pub signal: Option<i32>,
+    pub termination: Termination,
+    pub duration: Duration,
+    /// Captured standard output, when it was captured.
+    pub stdout: Option<Vec<u8>>,
+    /// Captured standard error, when it was captured.
+    pub stderr: Option<Vec<u8>>,
+}
+
+impl RunOutput {
+    pub fn success(&self) -> bool {
+        self.code == Some(0)
+    }
+
+    pub fn stdout_str(&self) -> String {
+        String::from_utf8_lossy(self.stdout.as_deref().unwrap_or_default()).into_owned()
+    }
+
+    pub fn stderr_str(&self) -> String {
+        String::from_utf8_lossy(self.stderr.as_deref().unwrap_or_default()).into_owned()
+    }
+}
+
+impl Default for Runner {
+    fn default() -> Self {
+        Runner::new()
+    }
+}
+
+impl Runner {
+    pub fn new() -> Runner {
+        Runner {
+            timeout: None,
+            grace: KILL_GRACE,
+            capture: false,
+            forward_interrupt: false,
+            input: None,
+        }
+    }
+
+    /// Stops the command once it runs for longer than `timeout`.
+    pub fn timeout(mut self, timeout: Duration) -> Runner {
+        self.timeout = Some(timeout);
+        self
+    }
+
+    /// Time given to the command to exit after being stopped, before it is killed.
+    pub fn grace(mut self, grace: Duration) -> Runner {
+        self.grace = grace;
+        self
+    }
+
+    /// Captures both standard output and standard error. A command whose output is
+    /// [`Stdout::Pipe`] always has its standard output captured.
+    pub fn capture(mut self, capture: bool) -> Runner {
+        self.capture = capture;
+        self
+    }
+
+    /// On Ctrl-C, interrupts the command and waits for it instead of exiting.
+    pub fn forward_interrupt(mut self, forward: bool) -> Runner {
+        self.forward_interrupt = forward;
+        self
+    }
+
+    /// Writes `input` to the standard input of the command, replacing its redirection.
+    pub fn input<I: Into<Vec<u8>>>(mut self, input: I) -> Runner {
+        self.input = Some(input.into());
+        self
+    }
+
+    pub async fn run(&self, command: &Command) -> io::Result<RunOutput> {
+        let mut cmd = tokio::process::Command::new(command.get_program());
+        cmd.args(command.get_args())
+            .envs(command.get_env().iter().map(|(k, v)| (k, v)))
+            .kill_on_drop(true);
+        cmd.stdin(match (&self.input, command.get_stdin()) {
+            (Some(_), _) => Stdio::piped(),
+            (None, Stdin::Inherit) => Stdio::inherit(),
+            (None, Stdin::Null) => Stdio::null(),
+            (None, Stdin::File(p)) => std::fs::File::open(p)?.into(),
+        });
+        cmd.stdout(match command.get_stdout() {
+            _ if self.capture => Stdio::piped(),
+            Stdout::Pipe => Stdio::piped(),
+            Stdout::Inherit => Stdio::inherit(),
+            Stdout::Null => Stdio::null(),
+            Stdout::Stderr => io::stderr().into(),
+            Stdout::File(p) => std::fs::File::create(p)?.into(),
+        });
+        cmd.stderr(if self.capture { Stdio::piped() } else { Stdio::inherit() });
+
+        debug!("spawning `{}`", command);
+        let start = Instant::now();
+        let mut child = cmd.spawn()?;
+        if let (Some(mut stdin), Some(input)) = (child.stdin.take(), self.input.clone()) {
+            tokio::spawn(async move {
+                // the command may exit without reading all of it
+                let _ = stdin.write_all(&input).await;
+            });
+        }
+        let stdout = child.stdout.take().map(Reader::spawn);
+        let stderr = child.stderr.take().map(Reader::spawn);
+
+        let (status, termination) = self.wait(&mut child).await?;
+        let duration = start.elapsed();
+        debug!("process ended with {} after {:?}", status, duration);
+
+        let stdout = match stdout {
+            Some(r) => Some(r.finish(self.grace).await),
+            None => None,
+        };
+        let stderr = match stderr {
+            Some(r) => Some(r.finish(self.grace).await),
+            None => None,
+        };
+        Ok(RunOutput {
+            code: status.code(),
+            signal: exit_signal(&status),
+            termination,
+            duration,
+            stdout,
+            stderr,
+        })
+    }
+
+    async fn wait(&self, child: &mut Child) -> io::Result<(ExitStatus, Termination)> {
+        let deadline = async {
+            match self.timeout {
+                Some(t) => tokio::time::sleep(t).await,
+                None => std::future::pending().await,
+            }
+        };
+        let interrupt = async {
+            if self.forward_interrupt {
+                tokio::signal::ctrl_c().await
+            } else {
+                std::future::pending().await
+            }
+        };
+        // an interrupt wins over the exit of a command that got it as well
+        let termination = tokio::select! {
+            biased;
+            r = interrupt => {
+                r?;
+                debug!("forwarding interrupt to process");
+                send_signal(child, Signal::Interrupt)?;
+                Termination::Interrupted
+            }
+            status = child.wait() => return Ok((status?, Termination::Exited)),
+            _ = deadline => {
+                warn!("process timed out, terminating it");
+                send_signal(child, Signal::Terminate)?;
+                Termination::TimedOut
+            }
+        };
+        let status = match timeout(self.grace, child.wait()).await {
+            Ok(status) => status?,
+            Err(_) => {
+                warn!("process didn't exit after {:?}, killing it", self.grace);
+                child.kill().await?;
+                child.wait().await?
+            }
+        };
+        Ok((status, termination))
+    }
+}
+
+enum Signal {
+    Interrupt,
+    Terminate,
+}
+
+#[cfg(unix)]
+fn send_signal(child: &mut Child, signal: Signal) -> io::Result<()> {
+    let signal = match signal {
+        Signal::Interrupt => libc::SIGINT,
+        Signal::Terminate => libc::SIGTERM,
+    };
+    // no pid means that the process has already been reaped
+    if let Some(pid) = child.id() {
+        // SAFETY: kill doesn't access any memory
+        if unsafe { libc::kill(pid as libc::pid_t, signal) } != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+fn send_signal(child: &mut Child, _signal: Signal) -> io::Result<()> {
+    child.start_kill()
+}
+
+#[cfg(unix)]
+fn exit_signal(status: &ExitStatus) -> Option<i32> {
+    use std::os::unix::process::ExitStatusExt;
+    status.signal()
+}
+
+#[cfg(not(unix))]
+fn exit_signal(_status: &ExitStatus) -> Option<i32> {
+    None
+}
+
+/// Collects a stream of the command in the background.
+struct Reader {
+    buffer: Arc<Mutex<Vec<u8>>>,
+    task: JoinHandle<()>,
+}
+
+impl Reader {
+    fn spawn<R: AsyncRead + Unpin + Send + 'static>(mut stream: R) -> Reader {
+        let buffer = Arc::new(Mutex::new(vec![]));
+        let shared = buffer.clone();
+        let task = tokio::spawn(async move {
+            let mut chunk = [0; 4096];
+            while let Ok(n @ 1..) = stream.read(&mut chunk).await {
+                shared.lock().unwrap().extend_from_slice(&chunk[..n]);
+            }
+        });
+        Reader { buffer, task }
+    }
+
+    /// Returns what has been read once the stream is closed, or after `grace` since a
+    /// process left in the background by the command can keep it open.
+    async fn finish(mut self, grace: Duration) -> Vec<u8> {
+        if timeout(grace, &mut self.task).await.is_err() {
+            debug!("stream still open after {:?}, leaving it", grace);
+            self.task.abort();
+        }
+        std::mem::take(&mut self.buffer.lock().unwrap())
+    }
+}
@@ -0,0 +1,92 @@
+//! POSIX shell quoting and word splitting.
+//!
+//! Used for the remote command given to ssh, which is run by the remote shell, for the `-e`
+//! command and for rendering commands that can be pasted in a shell (e.g. with `-n`).
+use std::borrow::Cow;
+use std::fmt::Display;
+
+/// Quotes `s` so that a POSIX shell reads it back as a single word, leaving it untouched when
+/// it contains only safe characters.
+pub fn quote(s: &str) -> Cow<'_, str> {
+    let safe = |c: char| c.is_ascii_alphanumeric() || "@%+=:,./-_".contains(c);
+    if !s.is_empty() && s.chars().all(safe) {
+        Cow::Borrowed(s)
+    } else {
+        Cow::Owned(format!("'{}'", s.replace('\'', "'\\''")))
+    }
+}
+
+/// Quotes every word of `args` and joins them with spaces.
+pub fn join<S: AsRef<str>>(args: &[S]) -> String {
+    args.iter()
+        .map(|a| quote(a.as_ref()))
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[derive(Debug, PartialEq)]
+pub enum SplitError {
+    UnterminatedQuote(char),
+    TrailingBackslash,
+}
+
+impl Display for SplitError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SplitError::UnterminatedQuote(q) => write!(f, "unterminated {} quote", q),
+            SplitError::TrailingBackslash => write!(f, "trailing backslash"),
+        }
+    }
+}
+
+/// Splits `s` into words as a POSIX shell would, honouring single and double quotes and
+/// backslash escapes. No expansion is performed.
+pub fn split(s: &str) -> Result<Vec<String>, SplitError> {
+    let mut words = vec![];
+    // None between words, so that `''` still makes an (empty) word
+    let mut word: Option<String> = None;
+    let mut chars = s.chars();
+    while let Some(c) = chars.next() {
+        match c {
+            ' ' | '\t' | '\n' => words.extend(word.take()),
+            '\\' => match chars.next() {
+                Some('\n') => {}
+                Some(e) => word.get_or_insert_with(String::new).push(e),
+                None => return Err(SplitError::TrailingBackslash),
+            },
+            '\'' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('\'') => break,
+                        Some(q) => w.push(q),
+                        None => return Err(SplitError::UnterminatedQuote('\'')),
+                    }
+                }
+            }
+            '"' => {
+                let w = word.get_or_insert_with(String::new);
+                loop {
+                    match chars.next() {
+                        Some('"') => break,
+                        // inside double quotes the backslash only escapes these
+                        Some('\\') => match chars.next() {
+                            Some(e @ ('$' | '`' | '"' | '\\')) => w.push(e),
+                            Some('\n') => {}
+                            Some(e) => {
+                                w.push('\\');
+                                w.push(e);
+                            }
+                            None => return Err(SplitError::UnterminatedQuote('"')),
+                        },
+                        Some(q) => w.push(q),
+                        None => return Err(SplitError::UnterminatedQuote('"')),
+                    }
+                }
+            }
+            c => word.get_or_insert_with(String::new).push(c),
+        }
+    }
+    words.extend(word);
+    Ok(words)
+}
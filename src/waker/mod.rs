@@ -1,5 +1,8 @@
 use mac_address::MacAddress;
 use reqwest::Method;
+use std::fmt::Display;
+
+use crate::net::RoutingError;
 #[cfg(feature = "serde")]
 use serde::{de::Error, {Deserialize, Deserializer, Serialize, Serializer}};
 #[cfg(feature = "serde")]
@@ -20,6 +23,27 @@ pub enum Waker {
     },
 }
 
+#[derive(Debug)]
+pub enum WakeError {
+    Routing(RoutingError),
+    Failed(String),
+}
+
+impl Display for WakeError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WakeError::Routing(e) => write!(f, "routing error: {}", e),
+            WakeError::Failed(e) => write!(f, "{}", e),
+        }
+    }
+}
+
+impl From<RoutingError> for WakeError {
+    fn from(value: RoutingError) -> Self {
+        WakeError::Routing(value)
+    }
+}
+
 #[cfg(feature = "serde")]
 pub fn mac_parser<'de, D>(deserializer: D) -> Result<MacAddress, D::Error>
 where
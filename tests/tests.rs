@@ -3,7 +3,7 @@
 use std::collections::HashSet;
 
 use futures::executor::block_on;
-use bodo_connect::{net::{ConnectionMethod, NetworkMap, Subnet}, ssh::{options::GenericOption, SSHOptionStore}};
+use bodo_connect::{net::{ConnectionMethod, NetworkMap, Subnet}, ssh::{options::GenericOption, SSHOptionStore, SSHProcess}};
 
 const NETWORKMAP_EXAMPLE: &str = r#"
 [
@@ -59,6 +59,15 @@ const NETWORKMAP_EXAMPLE: &str = r#"
 ]
 "#;
 
+/// Returns a listening socket, to be kept alive, and the address of a port that was just closed.
+fn open_and_closed_ports() -> (std::net::TcpListener, std::net::SocketAddr) {
+    let up = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+    let addr = down.local_addr().unwrap();
+    drop(down);
+    (up, addr)
+}
+
 #[cfg(feature = "wake")]
 #[test]
 fn migration_test() {
@@ -123,16 +132,19 @@ fn parsing_test() {
         HashSet::new(),
         #[cfg(feature = "wake")]
         Some(Waker::HttpWaker {method: Method::GET, url: "https://example.com".to_string()}),
+        #[cfg(feature = "sync")]
         Some(true),
+        #[cfg(feature = "direct")]
+        None,
     ));
 
     let nm = NetworkMap::try_from(vec![subnet]).unwrap();
     assert_eq!(block_on(nm.to_ssh(
         nm.get_host("x").unwrap(),
-        ConnectionMethod::ViaSubnet(None),
+        ConnectionMethod::ViaSubnet(vec![]),
         &["rm".to_owned(), "-rf".to_owned(), "/".to_owned()],
         None
-    )).to_string(), "ssh -J martian@mars.orbit:23 -p 5 x@10.8.5.2 rm -rf /");
+    )).unwrap().to_string(), "ssh -J martian@mars.orbit:23 -p 5 x@10.8.5.2 rm -rf /");
 }
 
 #[cfg(feature = "sshfs")]
@@ -140,7 +152,9 @@ fn parsing_test() {
 fn sshfs() {
     let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
     let target = nm.get_host("phobos").unwrap();
-    let proc = block_on(nm.to_sshfs(target, ConnectionMethod::ViaSubnet(None), "/home/pi".to_string(), "/mnt/temp".to_string()));
+    let proc = block_on(nm.to_sshfs(target, ConnectionMethod::ViaSubnet(vec![]), "/home/pi".to_string(), "/mnt/temp".to_string())).unwrap();
+    assert_eq!(proc.command().get_program(), "sudo");
+    assert_eq!(proc.get_args()[..3], ["sudo", "--preserve-env=SSH_AUTH_SOCK", "sshfs"]);
     println!("{}", proc)
 }
 
@@ -149,9 +163,9 @@ async fn uncertainty() {
     let nm: NetworkMap = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
 
     for _ in 0..3 {
-        let current_subnet = nm.find_current_subnet().await;
+        let current_subnet = nm.find_current_subnets().await;
 
-        let proc = nm.to_ssh(nm.get_host("mars").unwrap(), ConnectionMethod::ViaSubnet(current_subnet), &["echo".to_owned()], None).await;
+        let proc = nm.to_ssh(nm.get_host("mars").unwrap(), ConnectionMethod::ViaSubnet(current_subnet), &["echo".to_owned()], None).await.unwrap();
         assert_eq!(proc.to_string(), "ssh martian@example.com echo");
     }
 }
@@ -161,21 +175,1173 @@ fn custom_ssh_command() {
     let nm: NetworkMap = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
 
     let mars = nm.get_host("phobos").unwrap();
-    let _sub = nm.get_host_subnet(mars);
+    let _sub = nm.get_host_subnet(mars).unwrap();
     let mut opts = SSHOptionStore::new(Some("ssh -L 8000:localhost:5000".to_owned()));
-    opts.add_option(Box::new(GenericOption::Switch("v")));
-    let ssh = block_on(nm.to_ssh(mars, ConnectionMethod::ViaSubnet(None), &vec!["echo".to_owned()], Some(opts)));
+    opts.add_option(Box::new(GenericOption::Switch("v".into())));
+    let ssh = block_on(nm.to_ssh(mars, ConnectionMethod::ViaSubnet(vec![]), &["echo".to_owned()], Some(opts))).unwrap();
     assert_eq!(ssh.to_string(), "ssh -L 8000:localhost:5000 -J martian@example.com -p 444 -v rover@192.168.1.2 echo");
 
     // Use rsh for insecure but fastest connection
     let mut opts = SSHOptionStore::new(Some("rsh --debug".to_owned()));
-    opts.add_option(Box::new(GenericOption::Value("escape", "~".to_owned())));
+    opts.add_option(Box::new(GenericOption::Value("escape".into(), "~".to_owned())));
     let ssh = block_on(nm.to_ssh(
         nm.get_host("mars").unwrap(),
-        ConnectionMethod::ViaSubnet(None),
+        ConnectionMethod::ViaSubnet(vec![]),
         &["echo".to_owned()],
         Some(opts)
+    )).unwrap();
+    assert_eq!(ssh.to_string(), "rsh --debug --escape '~' martian@example.com echo");
+}
+
+#[test]
+fn shell_quoting() {
+    use bodo_connect::ssh::shell::{join, quote, split, SplitError};
+
+    let hostile = [
+        "plain", "", "two words", "it's", "\"double\"", "$HOME", "`id`", "$(id)", "a;b", "a && b",
+        "*.txt", "~", "back\\slash", "new\nline", "'", "''", "!event", "-rf",
+    ];
+    assert_eq!(quote("plain"), "plain");
+    assert_eq!(quote("it's"), r"'it'\''s'");
+    assert_eq!(split(&join(&hostile)).unwrap(), hostile);
+
+    // the shell reads every word back unchanged
+    let out = std::process::Command::new("sh")
+        .arg("-c")
+        .arg(format!("printf '%s\\0' {}", join(&hostile)))
+        .output()
+        .unwrap();
+    let words: Vec<&str> = std::str::from_utf8(&out.stdout).unwrap().split_terminator('\0').collect();
+    assert_eq!(words, hostile);
+
+    assert_eq!(
+        split(r#"ssh -o "User=me" -i ~/my\ key 'a b'"c d"\$x"#).unwrap(),
+        ["ssh", "-o", "User=me", "-i", "~/my key", "a bc d$x"]
+    );
+    assert_eq!(split("a 'b"), Err(SplitError::UnterminatedQuote('\'')));
+    assert_eq!(split("a\\"), Err(SplitError::TrailingBackslash));
+
+    let nm: NetworkMap = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
+    let command = ["echo".to_owned(), "$HOME; rm *".to_owned()];
+    let ssh = block_on(nm.to_ssh(nm.get_host("mars").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &command, None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh martian@example.com echo ''\\''$HOME; rm *'\\'''");
+}
+
+#[test]
+fn process_program() {
+    use bodo_connect::ssh::process::{Command, Process, Stdin, Stdout};
+    use subprocess::ExitStatus;
+
+    // the program is the first word, not always ssh
+    let mut proc: Box<dyn Process> = Box::new(SSHProcess::new(vec!["sh".to_owned(), "-c".to_owned(), "exit 3".to_owned()]));
+    assert_eq!(proc.to_string(), "sh -c 'exit 3'");
+    assert_eq!(proc.run().unwrap(), ExitStatus::Exited(3));
+
+    let cmd = Command::new("sh")
+        .args(["-c", "test \"$GREETING\" = 'hello world' && cat"])
+        .env("GREETING", "hello world")
+        .stdin(Stdin::Null)
+        .stdout(Stdout::Pipe);
+    assert_eq!(cmd.to_string(), "GREETING='hello world' sh -c 'test \"$GREETING\" = '\\''hello world'\\'' && cat' < /dev/null");
+    let captured = cmd.exec().unwrap().capture().unwrap();
+    assert!(captured.success());
+    assert_eq!(captured.stdout_str(), "");
+    assert_eq!(cmd.clone().stdout(Stdout::Stderr).to_string().rsplit_once(' ').unwrap().1, ">&2");
+}
+
+#[tokio::test]
+async fn process_runner() {
+    use std::time::Duration;
+    use bodo_connect::ssh::process::{Command, Stdout};
+    use bodo_connect::ssh::runner::{Runner, Termination};
+
+    let sh = |script: &str| Command::new("sh").args(["-c", script]);
+
+    let out = Runner::new().capture(true).input("world").run(&sh("printf \"hello $(cat)\"; echo oops >&2; exit 4")).await.unwrap();
+    assert_eq!(out.code, Some(4));
+    assert_eq!(out.signal, None);
+    assert_eq!(out.termination, Termination::Exited);
+    assert_eq!(out.stdout_str(), "hello world");
+    assert_eq!(out.stderr_str(), "oops\n");
+
+    // only the piped stdout is captured without capture mode
+    let out = Runner::new().run(&sh("echo out").stdout(Stdout::Pipe)).await.unwrap();
+    assert!(out.success());
+    assert_eq!(out.stdout_str(), "out\n");
+    assert_eq!(out.stderr, None);
+
+    // terminated on timeout, then killed when it ignores it
+    let out = Runner::new().timeout(Duration::from_millis(100)).run(&sh("sleep 5")).await.unwrap();
+    assert_eq!(out.termination, Termination::TimedOut);
+    assert_eq!((out.code, out.signal), (None, Some(15)));
+    assert!(out.duration < Duration::from_secs(2));
+    let out = Runner::new()
+        .timeout(Duration::from_millis(100))
+        .grace(Duration::from_millis(100))
+        .run(&sh("trap '' TERM; sleep 5"))
+        .await
+        .unwrap();
+    assert_eq!((out.termination, out.signal), (Termination::TimedOut, Some(9)));
+
+    assert!(Runner::new().run(&Command::new("/nonexistent/program")).await.is_err());
+}
+
+#[test]
+fn proxy_hops() {
+    use bodo_connect::ssh::Hop;
+
+    let hops = vec![
+        Hop::socks5("proxy.corp".to_owned(), 1080),
+        Hop::new("b".to_owned(), "bastion.example.com".to_owned(), 2222),
+        Hop::new("g".to_owned(), "10.0.0.1".to_owned(), 22),
+    ];
+    assert_eq!(
+        NetworkMap::gen_ssh_options(hops, None, None).args_gen(),
+        vec![
+            "-o",
+            "ProxyCommand=ssh -o 'ProxyCommand=ssh -o '\\''ProxyCommand=nc -X 5 -x proxy.corp:1080 bastion.example.com 2222'\\'' -p 2222 -W 10.0.0.1:22 b@bastion.example.com' -W %h:%p g@10.0.0.1",
+        ]
+    );
+    // the http proxy is used from the bastion
+    let hops = vec![
+        Hop::new("b".to_owned(), "bastion.example.com".to_owned(), 22),
+        Hop::http_connect("proxy.corp".to_owned(), 3128),
+    ];
+    assert_eq!(
+        NetworkMap::gen_ssh_options(hops, None, None).args_gen(),
+        vec!["-o", "ProxyCommand=ssh b@bastion.example.com nc -X connect -x proxy.corp:3128 %h %p"]
+    );
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "home.example.com",
+        "hosts": [
+          { "name": "gate", "ip": "10.0.0.1", "eport": 443, "user": "g", "proxy": { "kind": "command", "command": "wstunnel %h %p" } },
+          { "name": "box", "ip": "10.0.0.3", "user": "x" }
+        ]
+      }
+    ]
+    "#).unwrap()).unwrap();
+    let ssh = block_on(nm.to_ssh(nm.get_host("box").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -o 'ProxyCommand=ssh -o '\\''ProxyCommand=wstunnel home.example.com 443'\\'' -p 443 -W %h:%p g@home.example.com' x@10.0.0.3 true");
+    let ssh = block_on(nm.to_ssh(nm.get_host("gate").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -o 'ProxyCommand=wstunnel %h %p' -p 443 g@home.example.com true");
+}
+
+#[test]
+fn ssh_option_maps() {
+    use std::collections::BTreeMap;
+    use bodo_connect::ssh::Hop;
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "home.example.com",
+        "ssh_options": { "ServerAliveInterval": 30, "Compression": true },
+        "hosts": [
+          { "name": "gate", "ip": "10.0.0.1", "eport": 22, "user": "g", "ssh_options": { "IdentityFile": "~/.ssh/gate" } },
+          { "name": "box", "ip": "10.0.0.3", "user": "x", "ssh_options": { "ForwardAgent": "yes", "ServerAliveInterval": 60 } }
+        ]
+      }
+    ]
+    "#).unwrap()).unwrap();
+    let target = nm.get_host("box").unwrap();
+
+    let home = nm.get_host_subnet(target).unwrap();
+    let ssh = block_on(nm.to_ssh(target, ConnectionMethod::ViaSubnet(vec![home]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -o Compression=yes -o ForwardAgent=yes -o ServerAliveInterval=60 x@10.0.0.3 true");
+
+    let mut cli = SSHOptionStore::new(None);
+    cli.set_config_option("ServerAliveInterval".to_owned(), "90".to_owned());
+    let ssh = block_on(nm.to_ssh(target, ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], Some(cli))).unwrap();
+    assert_eq!(
+        ssh.to_string(),
+        "ssh -o 'ProxyCommand=ssh -o Compression=yes -o '\\''IdentityFile=~/.ssh/gate'\\'' -o ServerAliveInterval=30 -W %h:%p g@home.example.com' \
+         -o Compression=yes -o ForwardAgent=yes -o ServerAliveInterval=90 x@10.0.0.3 true"
+    );
+
+    // a single hop with options turns the whole route into nested ProxyCommands
+    let hops = |options: BTreeMap<String, String>| vec![
+        Hop::new("a".to_owned(), "a.example.com".to_owned(), 22),
+        Hop::new("b".to_owned(), "b.example.com".to_owned(), 22).with_options(options),
+        Hop::new("c".to_owned(), "c.example.com".to_owned(), 22),
+    ];
+    assert_eq!(
+        NetworkMap::gen_ssh_options(hops(BTreeMap::new()), None, None).args_gen(),
+        vec!["-J", "a@a.example.com,b@b.example.com,c@c.example.com"]
+    );
+    assert_eq!(
+        NetworkMap::gen_ssh_options(hops([("Compression".to_owned(), "yes".to_owned())].into()), None, None).args_gen(),
+        vec![
+            "-o",
+            "ProxyCommand=ssh -o 'ProxyCommand=ssh -o '\\''ProxyCommand=ssh -W b.example.com:22 a@a.example.com'\\'' -o Compression=yes -W c.example.com:22 b@b.example.com' -W %h:%p c@c.example.com",
+        ]
+    );
+}
+
+#[test]
+fn multi_valued_options() {
+    use bodo_connect::ssh::options::{ConfigOption, PortOption};
+
+    let mut opts = SSHOptionStore::new(None);
+    opts.add_option(Box::new(GenericOption::Value("L".into(), "8000:localhost:80".to_owned())));
+    opts.add_option(Box::new(GenericOption::Value("L".into(), "8443:localhost:443".to_owned())));
+    opts.add_option(Box::new(GenericOption::Value("L".into(), "8000:localhost:80".to_owned())));
+    opts.add_option(Box::new(PortOption::new(2222)));
+    opts.add_option(Box::new(PortOption::new(2200)));
+    opts.set_config_option("ServerAliveInterval".to_owned(), "30".to_owned());
+    opts.add_option(Box::new(ConfigOption::new("Compression".to_owned(), "yes".to_owned())));
+    opts.add_option(Box::new(GenericOption::Switch("t".into())));
+    opts.add_option(Box::new(GenericOption::Switch("v".into())));
+    opts.add_option(Box::new(GenericOption::Switch("t".into())));
+    assert_eq!(
+        opts.args_gen(),
+        vec![
+            "-L", "8000:localhost:80", "-L", "8443:localhost:443", "-p", "2200",
+            "-o", "ServerAliveInterval=30", "-o", "Compression=yes", "-tv",
+        ]
+    );
+
+    // keys are case insensitive, later options take precedence
+    let mut cli = SSHOptionStore::new(None);
+    cli.set_config_option("serveraliveinterval".to_owned(), "60".to_owned());
+    cli.add_option(Box::new(GenericOption::Value("o".into(), "Compression=no".to_owned())));
+    opts.merge(cli);
+    assert_eq!(
+        opts.args_gen()[6..10],
+        ["-o", "serveraliveinterval=60", "-o", "Compression=no"]
+    );
+
+    // names built at runtime
+    let mut opts = SSHOptionStore::new(None);
+    for forward in ["L", "R"] {
+        opts.add_option(Box::new(GenericOption::Value(forward.to_owned().into(), "8000:localhost:80".to_owned())));
+    }
+    assert_eq!(opts.args_gen(), vec!["-L", "8000:localhost:80", "-R", "8000:localhost:80"]);
+}
+
+#[tokio::test]
+async fn proxied_master_selection() {
+    use std::net::TcpListener;
+    use bodo_connect::config::{RouteSelection, Settings};
+
+    let closed = TcpListener::bind("127.0.0.1:0").unwrap().local_addr().unwrap().port();
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "127.0.0.1",
+        "hosts": [
+          {{ "name": "main", "ip": "10.0.0.1", "eport": {}, "priority": 1, "user": "m" }},
+          {{ "name": "corp", "ip": "10.0.0.2", "eport": 443, "priority": 2, "user": "c", "proxy": {{ "kind": "http", "host": "proxy.corp", "port": 3128 }} }},
+          {{ "name": "box", "ip": "10.0.0.3", "user": "x" }}
+        ]
+      }}
+    ]
+    "#, closed)).unwrap()).unwrap();
+    *nm.settings_mut() = Settings { detectors: vec![], ..Settings::default() };
+    let target = nm.get_host("box").unwrap();
+
+    // it can't be probed without its proxy, but it is used when the preferred master is down
+    let ssh = nm.to_ssh(target, ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None).await.unwrap();
+    assert_eq!(
+        ssh.to_string(),
+        "ssh -o 'ProxyCommand=ssh -o '\\''ProxyCommand=nc -X connect -x proxy.corp:3128 127.0.0.1 443'\\'' -p 443 -W %h:%p c@127.0.0.1' x@10.0.0.3 true"
+    );
+
+    nm.settings_mut().route_selection = RouteSelection::Latency;
+    let (method, probes) = nm.plan_connection(nm.get_host("box").unwrap()).await.unwrap();
+    assert!(matches!(method, ConnectionMethod::ViaMaster(_, ref e) if e.master.name == "corp"));
+    assert_eq!(probes.len(), 1);
+}
+
+const NETWORKMAP_NESTED: &str = r#"
+[
+  {
+    "subdomain": "office.example.com",
+    "hosts": [
+      { "name": "office", "ip": "10.0.0.1", "eport": 2222, "user": "boss" },
+      { "name": "gw", "ip": "10.0.0.5", "user": "gate" }
+    ]
+  },
+  {
+    "subdomain": "lab.office",
+    "behind": "gw",
+    "hosts": [
+      { "name": "bench", "ip": "10.1.0.1", "user": "tech" }
+    ]
+  },
+  {
+    "subdomain": "cage.lab",
+    "behind": "bench",
+    "hosts": [
+      { "name": "rack", "ip": "10.2.0.1", "port": 2200, "user": "root" }
+    ]
+  }
+]
+"#;
+
+#[test]
+fn nested_subnets() {
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_NESTED).unwrap()).unwrap();
+    let rack = nm.get_host("rack").unwrap();
+
+    let ssh = block_on(nm.to_ssh(rack, ConnectionMethod::ViaSubnet(vec![]), &["uptime".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J boss@office.example.com:2222,gate@10.0.0.5,tech@10.1.0.1 -p 2200 root@10.2.0.1 uptime");
+
+    let office = nm.get_host_subnet(nm.get_host("office").unwrap()).unwrap();
+    let ssh = block_on(nm.to_ssh(rack, ConnectionMethod::ViaSubnet(vec![office]), &["uptime".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J gate@10.0.0.5,tech@10.1.0.1 -p 2200 root@10.2.0.1 uptime");
+
+    let lab = nm.get_host_subnet(nm.get_host("bench").unwrap()).unwrap();
+    let ssh = block_on(nm.to_ssh(rack, ConnectionMethod::ViaSubnet(vec![lab]), &["uptime".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J tech@10.1.0.1 -p 2200 root@10.2.0.1 uptime");
+}
+
+#[test]
+fn nested_subnets_cycle() {
+    use bodo_connect::net::{NetworkMapError, RoutingError};
+
+    let mut subnets = serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_NESTED).unwrap();
+    subnets[1].behind = Some("rack".to_owned());
+    assert!(matches!(NetworkMap::try_from(subnets), Err(NetworkMapError::Routing(RoutingError::RoutingCycle(_)))));
+
+    let mut subnets = serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_NESTED).unwrap();
+    subnets[2].behind = Some("nowhere".to_owned());
+    assert!(matches!(NetworkMap::try_from(subnets), Err(NetworkMapError::Routing(RoutingError::UnknownGateway(..)))));
+}
+
+#[tokio::test]
+async fn master_failover() {
+    let (up, down) = open_and_closed_ports();
+    let (up_port, down_port) = (up.local_addr().unwrap().port(), down.port());
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "127.0.0.1",
+        "hosts": [
+          {{ "name": "backup", "ip": "10.0.0.2", "eport": {}, "priority": 2, "user": "b" }},
+          {{ "name": "main", "ip": "10.0.0.1", "eport": {}, "priority": 1, "user": "m" }},
+          {{ "name": "box", "ip": "10.0.0.3", "user": "x" }}
+        ]
+      }}
+    ]
+    "#, up_port, down_port)).unwrap()).unwrap();
+
+    let subnet = nm.get_host_subnet(nm.get_host("box").unwrap()).unwrap();
+    assert_eq!(subnet.get_masters().iter().map(|m| m.name.as_str()).collect::<Vec<_>>(), vec!["main", "backup"]);
+    assert_eq!(subnet.get_master().unwrap().name, "main");
+
+    let ssh = nm.to_ssh(nm.get_host("box").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None).await.unwrap();
+    assert_eq!(ssh.to_string(), format!("ssh -J b@127.0.0.1:{} x@10.0.0.3 true", up_port));
+}
+
+#[test]
+fn routing_errors() {
+    use bodo_connect::net::{Host, RoutingError};
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "headless.example.com",
+        "hosts": [
+          { "name": "lonely", "ip": "10.9.0.1", "user": "nobody" }
+        ]
+      }
+    ]
+    "#).unwrap()).unwrap();
+
+    let lonely = nm.get_host("lonely").unwrap();
+    assert!(matches!(
+        block_on(nm.to_ssh(lonely, ConnectionMethod::ViaSubnet(vec![]), &[], None)),
+        Err(RoutingError::NoMaster(s)) if s == "headless.example.com"
+    ));
+    assert!(matches!(nm.get_host_master(lonely), Err(RoutingError::NoMaster(_))));
+
+    let stranger: Host = serde_json::from_str(r#"{ "name": "stranger", "ip": "10.9.0.2", "user": "nobody" }"#).unwrap();
+    assert!(matches!(nm.get_host_subnet(&stranger), Err(RoutingError::NotInSubnet(h)) if h == "stranger"));
+    assert!(matches!(
+        block_on(nm.hops_gen(&stranger, &[])),
+        Err(RoutingError::NotInSubnet(_))
+    ));
+}
+
+#[test]
+fn explicit_via() {
+    use bodo_connect::net::RoutingError;
+
+    let config = r#"
+    [
+      {
+        "subdomain": "audit.example.com",
+        "hosts": [
+          { "name": "bastion", "ip": "10.5.0.1", "eport": 2222, "user": "audit" },
+          { "name": "logger", "ip": "10.5.0.2", "user": "log" }
+        ]
+      },
+      {
+        "subdomain": "home.example.com",
+        "hosts": [
+          { "name": "gate", "ip": "10.0.0.1", "eport": 22, "user": "g" },
+          { "name": "box", "ip": "10.0.0.3", "user": "x", "via": ["bastion", "logger"] }
+        ]
+      }
+    ]
+    "#;
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(config).unwrap()).unwrap();
+    let target = nm.get_host("box").unwrap();
+    let ssh = block_on(nm.to_ssh(target, ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J audit@audit.example.com:2222,log@10.5.0.2 x@10.0.0.3 true");
+
+    // the route is forced even from inside the target subnet
+    let home = nm.get_host_subnet(target).unwrap();
+    let ssh = block_on(nm.to_ssh(target, ConnectionMethod::ViaSubnet(vec![home]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J audit@audit.example.com:2222,log@10.5.0.2 x@10.0.0.3 true");
+
+    // override, as done by --via
+    nm.get_host_mut("box").unwrap().via = vec!["gate".to_owned()];
+    let ssh = block_on(nm.to_ssh(nm.get_host("box").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J g@home.example.com x@10.0.0.3 true");
+
+    nm.get_host_mut("box").unwrap().via = vec!["nowhere".to_owned()];
+    assert!(matches!(
+        block_on(nm.hops_gen(nm.get_host("box").unwrap(), &[])),
+        Err(RoutingError::UnknownVia(h, v)) if h == "box" && v == "nowhere"
     ));
-    assert_eq!(ssh.to_string(), "rsh --debug --escape ~ martian@example.com echo");
+    assert!(NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&config.replace("logger\"]", "box\"]")).unwrap()).is_err());
+}
+
+#[test]
+fn subnet_links() {
+    let config = r#"
+    [
+      {
+        "subdomain": "office.example.com",
+        "links": ["lab.example.com"],
+        "hosts": [
+          { "name": "boss", "ip": "10.0.0.1", "eport": 2222, "user": "boss" },
+          { "name": "desk", "ip": "10.0.0.2", "user": "me" }
+        ]
+      },
+      {
+        "subdomain": "lab.example.com",
+        "hosts": [
+          { "name": "tech", "ip": "10.1.0.1", "eport": 22, "user": "tech" },
+          { "name": "bench", "ip": "10.1.0.3", "user": "x" }
+        ]
+      },
+      {
+        "subdomain": "home.example.com",
+        "hosts": [
+          { "name": "gate", "ip": "10.2.0.1", "eport": 22, "user": "g" }
+        ]
+      }
+    ]
+    "#;
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(config).unwrap()).unwrap();
+    let subnet = |h: &str| nm.get_host_subnet(nm.get_host(h).unwrap()).unwrap();
+    let ssh = |target: &str, current: Vec<&Subnet>| {
+        block_on(nm.to_ssh(nm.get_host(target).unwrap(), ConnectionMethod::ViaSubnet(current), &["true".to_owned()], None))
+            .unwrap()
+            .to_string()
+    };
+
+    assert_eq!(ssh("bench", vec![subnet("desk")]), "ssh x@10.1.0.3 true");
+    // links work both ways
+    assert_eq!(ssh("desk", vec![subnet("bench")]), "ssh me@10.0.0.2 true");
+    assert_eq!(ssh("bench", vec![subnet("gate")]), "ssh -J tech@lab.example.com x@10.1.0.3 true");
+    assert_eq!(ssh("bench", vec![]), "ssh -J tech@lab.example.com x@10.1.0.3 true");
+
+    assert!(NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&config.replace("\"lab.example.com\"]", "\"nowhere.example.com\"]")).unwrap()).is_err());
+}
+
+#[test]
+fn relay_tunnel() {
+    let config = r#"
+    [
+      {
+        "subdomain": "vps.example.com",
+        "hosts": [
+          { "name": "vps", "ip": "10.8.0.1", "eport": 22, "user": "root" }
+        ]
+      },
+      {
+        "subdomain": "cgnat.example.com",
+        "hosts": [
+          { "name": "pi", "ip": "192.168.1.5", "user": "pi", "relay": { "host": "vps", "port": 2201 } }
+        ]
+      }
+    ]
+    "#;
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(config).unwrap()).unwrap();
+    let pi = nm.get_host("pi").unwrap();
+    let ssh = block_on(nm.to_ssh(pi, ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh -J root@vps.example.com -p 2201 pi@localhost true");
+
+    let home = nm.get_host_subnet(pi).unwrap();
+    let ssh = block_on(nm.to_ssh(pi, ConnectionMethod::ViaSubnet(vec![home]), &["true".to_owned()], None)).unwrap();
+    assert_eq!(ssh.to_string(), "ssh pi@192.168.1.5 true");
+
+    let unknown = config.replace(r#""host": "vps""#, r#""host": "nowhere""#);
+    assert!(NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&unknown).unwrap()).is_err());
+    let cycle = config.replace(r#""user": "root""#, r#""user": "root", "relay": { "host": "pi", "port": 2202 }"#);
+    assert!(NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&cycle).unwrap()).is_err());
+}
+
+#[tokio::test]
+async fn latency_selection() {
+    use std::net::TcpListener;
+    use bodo_connect::config::RouteSelection;
+
+    let up = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (up_port, down_port) = (up.local_addr().unwrap().port(), down.local_addr().unwrap().port());
+    drop(down);
+
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "127.0.0.1",
+        "hosts": [
+          {{ "name": "main", "ip": "10.0.0.1", "eport": {}, "priority": 1, "user": "m" }},
+          {{ "name": "backup", "ip": "10.0.0.2", "eport": {}, "priority": 2, "user": "b" }},
+          {{ "name": "box", "ip": "10.0.0.3", "user": "x", "addrs": ["127.0.0.1"], "port": {} }}
+        ]
+      }}
+    ]
+    "#, down_port, up_port, down_port)).unwrap()).unwrap();
+    nm.settings_mut().route_selection = RouteSelection::Latency;
+    // never consider the client inside the subnet
+    nm.settings_mut().detectors = vec![];
+
+    let target = nm.get_host("box").unwrap();
+    let (connection, probes) = nm.plan_connection(target).await.unwrap();
+    assert!(matches!(connection, ConnectionMethod::ViaMaster(_, ref e) if e.master.name == "backup"));
+    assert_eq!(probes.iter().filter(|p| p.latency.is_some()).count(), 1);
+    assert_eq!(probes.len(), if cfg!(feature = "direct") { 3 } else { 2 });
+
+    let ssh = nm.to_ssh(target, connection, &["true".to_owned()], None).await.unwrap();
+    assert_eq!(ssh.to_string(), format!("ssh -J b@127.0.0.1:{} -p {} x@10.0.0.3 true", up_port, down_port));
+}
+
+#[tokio::test]
+async fn multiple_endpoints() {
+    use std::collections::HashMap;
+    use std::net::TcpListener;
+
+    let up = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (up_port, down_port) = (up.local_addr().unwrap().port(), down.local_addr().unwrap().port());
+    drop(down);
+
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "home.example.com",
+        "endpoints": [
+          {{ "host": "127.0.0.2", "port": {} }},
+          {{ "host": "127.0.0.1", "port": {} }}
+        ],
+        "hosts": [
+          {{ "name": "main", "ip": "10.0.0.1", "eport": 22, "user": "m" }},
+          {{ "name": "box", "ip": "10.0.0.3", "user": "x" }}
+        ]
+      }}
+    ]
+    "#, down_port, up_port)).unwrap()).unwrap();
+    nm.settings_mut().detectors = vec![];
+
+    let subnet = nm.get_subnet("home.example.com").unwrap();
+    assert_eq!(subnet.get_entries().len(), 2);
+    let entry = nm.select_entry(subnet).await.unwrap();
+    assert_eq!(entry.address(), ("127.0.0.1".to_owned(), up_port));
+
+    let ssh = nm.to_ssh(nm.get_host("box").unwrap(), ConnectionMethod::ViaSubnet(vec![]), &["true".to_owned()], None).await.unwrap();
+    assert_eq!(ssh.to_string(), format!("ssh -J m@127.0.0.1:{} x@10.0.0.3 true", up_port));
+
+    let resolved = nm.resolve_subdomains().await;
+    assert!(!resolved.contains_key("home.example.com"));
+    let subdomain = |ip: &str, r: &HashMap<&str, Vec<_>>| nm.match_external_ip(ip.parse().unwrap(), r).map(|s| s.subdomain.clone());
+    assert_eq!(subdomain("127.0.0.1", &resolved).as_deref(), Some("home.example.com"));
+    assert_eq!(subdomain("127.0.0.2", &resolved).as_deref(), Some("home.example.com"));
+    assert_eq!(subdomain("127.0.0.3", &resolved), None);
 }
 
+const FIB_TRIE: &str = "Main:
+  +-- 0.0.0.0/0 3 0 5
+     |-- 0.0.0.0
+        /0 universe UNICAST
+     +-- 127.0.0.0/8 2 0 2
+        +-- 127.0.0.0/31 1 0 0
+           |-- 127.0.0.0
+              /8 host LOCAL
+           |-- 127.0.0.1
+              /32 host LOCAL
+     +-- 192.168.1.0/24 2 0 2
+        |-- 192.168.1.23
+           /32 host LOCAL
+        |-- 192.168.1.255
+           /32 link BROADCAST
+Local:
+     +-- 192.168.1.0/24 2 0 2
+        |-- 192.168.1.23
+           /32 host LOCAL
+";
+
+#[test]
+fn local_network_parsing() {
+    use std::net::IpAddr;
+    use bodo_connect::net::local;
+
+    let parse = |s: &str| s.parse::<IpAddr>().unwrap();
+    assert_eq!(local::parse_fib_trie(FIB_TRIE), vec![parse("127.0.0.1"), parse("192.168.1.23")]);
+    assert_eq!(
+        local::parse_if_inet6("fd000000000000000000000000000002 04 40 00 82     eth0\n00000000000000000000000000000001 01 80 10 80       lo\n"),
+        vec![parse("fd00::2"), parse("::1")]
+    );
+    assert_eq!(
+        local::parse_route("Iface\tDestination\tGateway\tFlags\neth0\t00000000\t0101A8C0\t0003\neth0\t0001A8C0\t00000000\t0001\n"),
+        vec![parse("192.168.1.1")]
+    );
+    assert_eq!(
+        local::parse_ipv6_route("00000000000000000000000000000000 00 00000000000000000000000000000000 00 fe800000000000000000000000000001 00000400 00000001 00000000 00000003 eth0\n"),
+        vec![parse("fe80::1")]
+    );
+
+    // getifaddrs sees the same addresses as procfs
+    let ifaddrs: Vec<IpAddr> = local::read_ifaddrs().into_iter().filter_map(|(_, a)| a).collect();
+    assert!(ifaddrs.contains(&parse("127.0.0.1")));
+    if std::path::Path::new("/proc/net/fib_trie").exists() {
+        assert!(local::parse_fib_trie(&std::fs::read_to_string("/proc/net/fib_trie").unwrap()).iter().all(|a| ifaddrs.contains(a)));
+    }
+}
+
+#[test]
+fn local_subnet_detection() {
+    use std::net::IpAddr;
+    use bodo_connect::net::detector::DetectorConfig;
+    use bodo_connect::net::local::LocalNetwork;
+
+    let parse = |s: &str| s.parse::<IpAddr>().unwrap();
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "home.example.com",
+        "cidrs": ["192.168.1.0/24"],
+        "gateway": "192.168.1.1",
+        "hosts": []
+      },
+      {
+        "subdomain": "loopback.example.com",
+        "cidrs": ["127.0.0.0/8", "::1/128"],
+        "hosts": []
+      }
+    ]
+    "#).unwrap()).unwrap();
+
+    let subnets = serde_json::from_str::<Vec<Subnet>>(&serde_json::to_string(&nm).unwrap()).unwrap();
+    let home = subnets.iter().find(|s| s.subdomain == "home.example.com").unwrap();
+    assert_eq!(home.gateway, Some(parse("192.168.1.1")));
+    let local = |addr: &str, gateway: &str| LocalNetwork {
+        addrs: vec![parse(addr)],
+        gateways: vec![parse(gateway)],
+        gateway_macs: vec![],
+    };
+    assert!(home.matches_local(&local("192.168.1.23", "192.168.1.1")));
+    assert!(!home.matches_local(&local("192.168.1.23", "192.168.1.254")));
+    assert!(!home.matches_local(&local("10.0.0.2", "192.168.1.1")));
+
+    let loopback = subnets.iter().find(|s| s.subdomain == "loopback.example.com").unwrap();
+    assert!(loopback.matches_local(&local("::1", "192.168.1.1")));
+    assert!(!home.matches_local(&local("127.0.0.1", "192.168.1.1")));
+
+    // the real interfaces can be in any of the subnets, but loopback is always there
+    let cidr = [DetectorConfig::Cidr.build()];
+    if cfg!(target_os = "linux") {
+        assert!(block_on(nm.detect_subnets(&cidr)).iter().any(|s| s.subdomain == "loopback.example.com"));
+    }
+    nm.add_subnet(Subnet::new("empty.example.com".to_owned(), None));
+    assert!(block_on(nm.detect_subnets(&cidr)).iter().all(|s| s.subdomain != "empty.example.com"));
+}
+
+#[test]
+fn gateway_mac_fingerprint() {
+    use std::net::IpAddr;
+    use bodo_connect::net::local::{self, LocalNetwork};
+
+    let neighbours = local::parse_arp("IP address       HW type     Flags       HW address            Mask     Device
+192.168.1.1      0x1         0x2         02:FC:00:00:00:05     *        eth0
+192.168.1.7      0x1         0x0         00:00:00:00:00:00     *        eth0
+");
+    assert_eq!(neighbours, vec![("192.168.1.1".parse::<IpAddr>().unwrap(), "02:fc:00:00:00:05".to_owned())]);
+
+    let mut home = serde_json::from_str::<Subnet>(r#"
+    {
+        "subdomain": "home.example.com",
+        "cidrs": ["192.168.1.0/24"],
+        "gateway_mac": "02-fc-00-00-00-05",
+        "hosts": []
+    }
+    "#).unwrap();
+    let mut local = LocalNetwork {
+        addrs: vec!["192.168.1.23".parse().unwrap()],
+        gateways: vec!["192.168.1.1".parse().unwrap()],
+        gateway_macs: vec!["02:fc:00:00:00:05".to_owned()],
+    };
+    assert!(home.matches_local(&local));
+    local.gateway_macs = vec!["aa:bb:cc:dd:ee:ff".to_owned()];
+    assert!(!home.matches_local(&local));
+    assert!(!home.matches_gateway_mac(&local));
+    home.gateway_mac = None;
+    assert!(home.matches_local(&local));
+}
+
+#[tokio::test]
+async fn host_key_pinning() {
+    use std::time::{Duration, Instant};
+    use bodo_connect::net::Host;
+    use bodo_connect::ssh::keyscan::{host_key_fingerprints, parse_fingerprints};
+
+    let fingerprints = parse_fingerprints("256 SHA256:Qm8E2bxN6hZtdH1ZpgxG2zV7hEXvJ9Xr3Qp1wZ4Y0cA 10.0.0.1 (ED25519)
+3072 SHA256:p2nV9zZ5q0sJ7mJ2pQ3f6lRkT3u1cW6yE0o9xB8aNfM 10.0.0.1 (RSA)
+");
+    assert_eq!(fingerprints.len(), 2);
+
+    let mut host: Host = serde_json::from_str(r#"
+    {
+        "name": "earth",
+        "ip": "10.0.0.1",
+        "eport": 22,
+        "user": "human",
+        "host_key": "p2nV9zZ5q0sJ7mJ2pQ3f6lRkT3u1cW6yE0o9xB8aNfM"
+    }
+    "#).unwrap();
+    assert!(host.matches_host_key(&fingerprints));
+    assert!(!host.matches_host_key(&fingerprints[..1]));
+    assert!(!host.matches_host_key(&[]));
+    host.host_key = None;
+    assert!(host.matches_host_key(&[]));
+
+    // a port that never answers doesn't hold the scan past its limit
+    let (silent, _) = open_and_closed_ports();
+    let start = Instant::now();
+    assert!(host_key_fingerprints("127.0.0.1", silent.local_addr().unwrap().port(), Duration::from_millis(300)).await.is_empty());
+    assert!(start.elapsed() < Duration::from_millis(900));
+}
+
+#[tokio::test]
+async fn detector_chain() {
+    use bodo_connect::config::Settings;
+    use bodo_connect::net::detector::DetectorConfig;
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
+    let settings: Settings = serde_json::from_str(r#"
+    {
+        "detectors": [
+            { "kind": "command", "command": "false", "subnet": "example.com" },
+            { "kind": "command", "command": "sleep 5", "subnet": "example.com", "timeout": 100 },
+            { "kind": "command", "command": "echo earth.orbit" },
+            { "kind": "master_probe" }
+        ]
+    }
+    "#).unwrap();
+    assert_eq!(settings.detection_threshold, 50);
+    assert_eq!(settings.detectors[3], DetectorConfig::MasterProbe);
+    assert_eq!(Settings::default().detectors, DetectorConfig::default_chain());
+
+    // the hung command is stopped after its timeout
+    let chain: Vec<_> = settings.detectors.iter().map(|d| d.build()).collect();
+    let start = std::time::Instant::now();
+    assert_eq!(nm.detect_subnets(&chain).await[0].subdomain, "earth.orbit");
+    assert!(start.elapsed() < std::time::Duration::from_secs(2));
+
+    if cfg!(target_os = "linux") {
+        let chain = [DetectorConfig::Interface { interface: "lo".to_owned(), subnet: "example.com".to_owned() }.build()];
+        assert_eq!(nm.detect_subnets(&chain).await[0].subdomain, "example.com");
+    }
+    let chain = [DetectorConfig::Interface { interface: "nonexistent0".to_owned(), subnet: "example.com".to_owned() }.build()];
+    assert!(nm.detect_subnets(&chain).await.is_empty());
+
+    assert_eq!("gateway_mac".parse::<DetectorConfig>(), Ok(DetectorConfig::GatewayMac));
+    assert!("command".parse::<DetectorConfig>().is_err());
+}
+
+#[tokio::test]
+async fn master_probe_online() {
+    use bodo_connect::config::Settings;
+    use bodo_connect::net::detector::DetectorConfig;
+    use bodo_connect::net::external_ip::ConnectivityCheck;
+
+    let (up, down) = open_and_closed_ports();
+    let up_port = up.local_addr().unwrap().port();
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "home.example.com",
+        "hosts": [
+          {{ "name": "pi", "ip": "127.0.0.1", "port": {}, "eport": 22, "user": "pi" }}
+        ]
+      }}
+    ]
+    "#, up_port)).unwrap()).unwrap();
+    *nm.settings_mut() = Settings { detectors: vec![], ..Settings::default() };
+    let chain = [DetectorConfig::MasterProbe.build()];
+
+    // offline, the open port of the master is enough
+    nm.settings_mut().connectivity_checks = vec![ConnectivityCheck { port: down.port(), ..ConnectivityCheck::new("127.0.0.1") }];
+    assert_eq!(nm.detect_subnets(&chain).await.len(), 1);
+    // online, any network could have that port open
+    nm.settings_mut().connectivity_checks = vec![ConnectivityCheck { port: up_port, ..ConnectivityCheck::new("127.0.0.1") }];
+    assert!(nm.detect_subnets(&chain).await.is_empty());
+    // without external lookups the connectivity is unknown, as when offline
+    nm.settings_mut().external_lookups = false;
+    assert_eq!(nm.detect_subnets(&chain).await.len(), 1);
+}
+
+#[test]
+fn multiple_current_subnets() {
+    use bodo_connect::net::detector::DetectorConfig;
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "home.example.com",
+        "cidrs": ["127.0.0.0/8"],
+        "hosts": [
+          { "name": "router", "ip": "192.168.1.1", "eport": 2222, "user": "root" },
+          { "name": "nas", "ip": "192.168.1.10", "user": "admin" }
+        ]
+      },
+      {
+        "subdomain": "office.example.com",
+        "cidrs": ["127.0.0.1/32"],
+        "hosts": [
+          { "name": "bastion", "ip": "10.8.0.1", "eport": 22, "user": "ops" },
+          { "name": "build", "ip": "10.8.0.20", "user": "ci" }
+        ]
+      },
+      {
+        "subdomain": "lab.example.com",
+        "hosts": [
+          { "name": "gate", "ip": "10.9.0.1", "eport": 2200, "user": "lab" },
+          { "name": "scope", "ip": "10.9.0.5", "user": "lab" }
+        ]
+      }
+    ]
+    "#).unwrap()).unwrap();
+
+    if cfg!(target_os = "linux") {
+        let mut detected: Vec<_> = block_on(nm.detect_subnets(&[DetectorConfig::Cidr.build()]))
+            .iter()
+            .map(|s| s.subdomain.clone())
+            .collect();
+        detected.sort();
+        assert_eq!(detected, vec!["home.example.com", "office.example.com"]);
+    }
+
+    let current = || vec![
+        nm.get_subnet("home.example.com").unwrap(),
+        nm.get_subnet("office.example.com").unwrap(),
+    ];
+    let ssh = |host: &str| block_on(nm.to_ssh(
+        nm.get_host(host).unwrap(),
+        ConnectionMethod::ViaSubnet(current()),
+        &["true".to_owned()],
+        None
+    )).unwrap().to_string();
+    assert_eq!(ssh("nas"), "ssh admin@192.168.1.10 true");
+    assert_eq!(ssh("build"), "ssh ci@10.8.0.20 true");
+    assert_eq!(ssh("scope"), "ssh -J lab@lab.example.com:2200 lab@10.9.0.5 true");
+}
+
+#[tokio::test]
+async fn ipv6_support() {
+    use bodo_connect::net::local::LocalNetwork;
+
+    let nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      {
+        "subdomain": "2001:db8::1",
+        "eip6": "2001:db8:1::/48",
+        "hosts": [
+          { "name": "router", "ip": "fd00::1", "eport": 2222, "user": "root" },
+          { "name": "nas", "ip": "fd00::5", "user": "admin" },
+          { "name": "web", "ip": "fd00::6", "ipv6": "2001:db8:1::6", "user": "www" }
+        ]
+      },
+      {
+        "subdomain": "192.0.2.1",
+        "hosts": [
+          { "name": "gate", "ip": "10.0.0.1", "eport": 22, "user": "gate" },
+          { "name": "box", "ip": "10.0.0.2", "user": "box" }
+        ]
+      }
+    ]
+    "#).unwrap()).unwrap();
+
+    let ssh = |host: &str| block_on(nm.to_ssh(
+        nm.get_host(host).unwrap(),
+        ConnectionMethod::ViaSubnet(vec![]),
+        &["true".to_owned()],
+        None
+    )).unwrap().to_string();
+    assert_eq!(ssh("nas"), "ssh -J 'root@[2001:db8::1]:2222' admin@fd00::5 true");
+    assert_eq!(ssh("box"), "ssh -J gate@192.0.2.1 box@10.0.0.2 true");
+    if LocalNetwork::read().has_global_ipv6() {
+        assert_eq!(ssh("web"), "ssh www@2001:db8:1::6 true");
+    } else {
+        assert_eq!(ssh("web"), "ssh -J 'root@[2001:db8::1]:2222' www@fd00::6 true");
+    }
+
+    let resolved = nm.resolve_subdomains().await;
+    let subdomain = |ip: &str| nm.match_external_ip(ip.parse().unwrap(), &resolved).map(|s| s.subdomain.as_str());
+    assert_eq!(subdomain("2001:db8:1:2::42"), Some("2001:db8::1"));
+    assert_eq!(subdomain("2001:db8::1"), Some("2001:db8::1"));
+    assert_eq!(subdomain("192.0.2.1"), Some("192.0.2.1"));
+    assert_eq!(subdomain("2001:db8:2::42"), None);
+
+    let local = |addr: &str, gateway: &str| LocalNetwork {
+        addrs: vec![addr.parse().unwrap()],
+        gateways: vec![gateway.parse().unwrap()],
+        gateway_macs: vec![],
+    };
+    assert!(local("2001:db8:1::23", "fe80::1").has_global_ipv6());
+    assert!(!local("fd00::23", "fe80::1").has_global_ipv6());
+    assert!(!local("2001:db8:1::23", "192.168.1.1").has_global_ipv6());
+
+    let listener = std::net::TcpListener::bind("[::1]:0");
+    if let Ok(listener) = listener {
+        let port = listener.local_addr().unwrap().port();
+        let probe = bodo_connect::net::Probe::tcp("::1", port, std::time::Duration::from_secs(1)).await;
+        assert_eq!(probe.target, format!("[::1]:{}", port));
+        assert!(probe.latency.is_some());
+    }
+}
+
+#[tokio::test]
+async fn concurrent_probing() {
+    use std::net::TcpListener;
+    use std::time::Duration;
+    use bodo_connect::config::Settings;
+    use bodo_connect::net::probe::{any_reachable, first_reachable};
+
+    let up = TcpListener::bind("127.0.0.1:0").unwrap();
+    let other = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let target = |l: &TcpListener| ("127.0.0.1".to_owned(), l.local_addr().unwrap().port());
+    let (up, other, down) = (target(&up), target(&other), { let d = target(&down); drop(down); d });
+    let limit = Duration::from_millis(500);
+
+    assert_eq!(first_reachable(&[down.clone(), up.clone(), other.clone()], limit).await, Some(1));
+    assert_eq!(first_reachable(&[other.clone(), up.clone()], limit).await, Some(0));
+    assert_eq!(first_reachable(std::slice::from_ref(&down), limit).await, None);
+    assert_eq!(first_reachable(&[], limit).await, None);
+    assert!(any_reachable(&[down.clone(), up], limit).await);
+    assert!(!any_reachable(&[down], limit).await);
+
+    let settings: Settings = serde_json::from_str(r#"{ "probe_timeout": 300 }"#).unwrap();
+    assert_eq!(settings.probe_timeout, 300);
+    assert_eq!(Settings::default().probe_timeout, 2000);
+}
+
+#[cfg(feature = "direct")]
+#[tokio::test]
+async fn direct_race() {
+    use std::net::TcpListener;
+
+    let up = TcpListener::bind("127.0.0.1:0").unwrap();
+    let down = TcpListener::bind("127.0.0.1:0").unwrap();
+    let (up_port, down_port) = (up.local_addr().unwrap().port(), down.local_addr().unwrap().port());
+    drop(down);
+
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(&format!(r#"
+    [
+      {{
+        "subdomain": "127.0.0.1",
+        "hosts": [
+          {{ "name": "gate", "ip": "10.0.0.1", "eport": {}, "user": "g" }},
+          {{ "name": "near", "ip": "10.0.0.2", "user": "n", "addrs": ["127.0.0.1"], "port": {} }},
+          {{ "name": "far", "ip": "10.0.0.3", "user": "f", "addrs": ["127.0.0.1"], "port": {} }}
+        ]
+      }}
+    ]
+    "#, up_port, up_port, down_port)).unwrap()).unwrap();
+    nm.settings_mut().detectors = vec![];
+    nm.settings_mut().race_head_start = 50;
+
+    let (connection, probes) = nm.plan_connection(nm.get_host("near").unwrap()).await.unwrap();
+    assert!(matches!(connection, ConnectionMethod::Direct(a) if a == "127.0.0.1"));
+    assert!(probes.is_empty());
+
+    let (connection, probes) = nm.plan_connection(nm.get_host("far").unwrap()).await.unwrap();
+    // the entry of the probed route is used, without selecting it again
+    assert!(matches!(connection, ConnectionMethod::ViaMaster(_, ref e) if e.master.name == "gate"));
+    assert_eq!(probes.len(), 1);
+    assert_eq!(probes[0].target, format!("127.0.0.1:{}", up_port));
+    assert!(probes[0].latency.is_some());
+}
+
+#[tokio::test]
+async fn external_ip_providers() {
+    use std::io::{Read, Write};
+    use std::net::TcpListener;
+    use bodo_connect::config::Settings;
+    use bodo_connect::net::external_ip::{get_ips, is_online, ConnectivityCheck, IpFamily, IpProvider, ResponseParser};
+
+    let json = ResponseParser::Json { field: "ip".to_owned() };
+    let trace = ResponseParser::KeyValue { key: "ip".to_owned() };
+    assert_eq!(ResponseParser::Plain.parse("203.0.113.7\n"), Some("203.0.113.7".parse().unwrap()));
+    assert_eq!(json.parse(r#"{"ip": "2001:db8::7"}"#), Some("2001:db8::7".parse().unwrap()));
+    assert_eq!(trace.parse("fl=123\nip=203.0.113.7\nts=1\n"), Some("203.0.113.7".parse().unwrap()));
+    assert_eq!(ResponseParser::Plain.parse("<html>"), None);
+    assert_eq!(json.parse(r#"{"address": "203.0.113.7"}"#), None);
+
+    // a tiny http server answering every request with the given bodies, in order
+    let serve = |bodies: Vec<&'static str>| {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/", listener.local_addr().unwrap());
+        std::thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let _ = stream.read(&mut [0; 1024]).unwrap();
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+            }
+        });
+        url
+    };
+    let provider = |url: String, parser: ResponseParser, family: Option<IpFamily>| {
+        IpProvider { parser, ..IpProvider::new(&url, family) }
+    };
+    let providers = vec![
+        provider(serve(vec![r#"{"ip":"203.0.113.7"}"#]), json, None),
+        // unreachable, ipv4 comes from the first provider
+        provider(serve(vec![]), ResponseParser::Plain, Some(IpFamily::Ipv4)),
+        provider(serve(vec!["198.51.100.1"]), ResponseParser::Plain, None),
+        provider(serve(vec!["ip=2001:db8::7"]), trace, Some(IpFamily::Ipv6)),
+    ];
+    assert_eq!(get_ips(&providers).await, vec!["203.0.113.7".parse::<std::net::IpAddr>().unwrap(), "2001:db8::7".parse().unwrap()]);
+    assert!(get_ips(&[]).await.is_empty());
+
+    // the families are asked concurrently
+    let silent = TcpListener::bind("127.0.0.1:0").unwrap();
+    let url = format!("http://{}/", silent.local_addr().unwrap());
+    let slow = |family| IpProvider { timeout: 300, ..IpProvider::new(&url, Some(family)) };
+    let start = std::time::Instant::now();
+    assert!(get_ips(&[slow(IpFamily::Ipv4), slow(IpFamily::Ipv6)]).await.is_empty());
+    assert!(start.elapsed() < std::time::Duration::from_millis(550));
+
+    let up = TcpListener::bind("127.0.0.1:0").unwrap();
+    let check = ConnectivityCheck { port: up.local_addr().unwrap().port(), ..ConnectivityCheck::new("127.0.0.1") };
+    assert!(is_online(&[check]).await);
+    assert!(is_online(&[]).await);
+
+    let settings: Settings = serde_json::from_str(r#"
+    {
+        "external_lookups": false,
+        "ip_providers": [
+            { "url": "https://ip.example.com/trace", "parser": { "kind": "key_value", "key": "ip" }, "timeout": 500 }
+        ],
+        "connectivity_checks": [ { "host": "proxy.example.com", "port": 3128 } ]
+    }
+    "#).unwrap();
+    assert!(!settings.external_lookups);
+    assert_eq!(settings.ip_providers[0].timeout, 500);
+    assert_eq!(settings.ip_providers[0].family, None);
+    assert_eq!(settings.connectivity_checks[0].timeout, 2000);
+    assert!(Settings::default().external_lookups);
+    assert_eq!(Settings::default().ip_providers, IpProvider::default_providers());
+}
+
+#[tokio::test]
+async fn detection_cache() {
+    use bodo_connect::net::cache::{digest, DetectionCache};
+    use bodo_connect::net::detector::DetectorConfig;
+    use bodo_connect::net::local::LocalNetwork;
+
+    let path = std::env::temp_dir().join(format!("bodo_connect_test_{}", std::process::id())).join("detection.json");
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(NETWORKMAP_EXAMPLE).unwrap()).unwrap();
+    nm.settings_mut().detectors = vec![DetectorConfig::Command { command: "echo earth.orbit".to_owned(), subnet: None, timeout: None }];
+    nm.settings_mut().cache_path = Some(path.clone());
+
+    let subdomains = |s: Vec<&Subnet>| s.iter().map(|s| s.subdomain.clone()).collect::<Vec<_>>();
+    assert_eq!(subdomains(nm.find_current_subnets().await), vec!["earth.orbit"]);
+    assert!(path.exists());
+
+    // the cached result is used, without running the detectors
+    let (_, key) = nm.detection_key().unwrap();
+    let mut cache = DetectionCache::load(&path);
+    assert_eq!(cache.get_subnets(&key, 600), Some(&["earth.orbit".to_owned()][..]));
+    cache.set_subnets(&key, vec!["example.com".to_owned()]);
+    cache.save(&path, 600).unwrap();
+    assert_eq!(subdomains(nm.find_current_subnets().await), vec!["example.com"]);
+
+    // another detectors chain doesn't use it, and nothing detected isn't cached
+    nm.settings_mut().detectors = vec![];
+    assert_ne!(nm.detection_key().unwrap().1, key);
+    assert!(nm.find_current_subnets().await.is_empty());
+    assert_eq!(DetectionCache::load(&path).get_subnets(&nm.detection_key().unwrap().1, 600), None);
+
+    // bypassed
+    nm.settings_mut().detectors = vec![DetectorConfig::Command { command: "echo earth.orbit".to_owned(), subnet: None, timeout: None }];
+    nm.settings_mut().cache_ttl = 0;
+    assert_eq!(subdomains(nm.find_current_subnets().await), vec!["earth.orbit"]);
+    nm.settings_mut().cache_ttl = 600;
+
+    let key = LocalNetwork::read().fingerprint();
+    let mut cache = DetectionCache::load(&path);
+    assert_eq!(cache.get_subnets("another network", 600), None);
+    cache.set_external_ips(&key, vec!["203.0.113.7".parse().unwrap()]);
+    cache.save(&path, 600).unwrap();
+    assert_eq!(DetectionCache::load(&path).get_external_ips(&key, 600), Some(&["203.0.113.7".parse().unwrap()][..]));
+
+    DetectionCache::flush(&path).unwrap();
+    DetectionCache::flush(&path).unwrap();
+    assert!(!path.exists());
+
+    let local = |addr: &str| LocalNetwork { addrs: vec![addr.parse().unwrap()], gateways: vec![], gateway_macs: vec![] };
+    assert_eq!(local("192.168.1.2").fingerprint(), local("192.168.1.2").fingerprint());
+    assert_ne!(local("192.168.1.2").fingerprint(), local("10.0.0.2").fingerprint());
+    // the keys must survive toolchain upgrades
+    assert_eq!(digest(""), "cbf29ce484222325");
+    assert_eq!(digest("a"), "af63dc4c8601ec8c");
+    assert_eq!(local("192.168.1.2").fingerprint(), digest("192.168.1.2"));
+    std::fs::remove_dir_all(path.parent().unwrap()).unwrap();
+}
+
+#[tokio::test]
+async fn subdomain_resolution() {
+    use std::time::Instant;
+    use bodo_connect::net::cache::DetectionCache;
+
+    let mut nm = NetworkMap::try_from(serde_json::from_str::<Vec<Subnet>>(r#"
+    [
+      { "subdomain": "192.0.2.1", "hosts": [] },
+      { "subdomain": "2001:db8::1", "hosts": [] },
+      { "subdomain": "fixed.example.com", "eip": "198.51.100.1", "hosts": [] },
+      { "subdomain": "unresolvable.invalid", "hosts": [] }
+    ]
+    "#).unwrap()).unwrap();
+    let path = std::env::temp_dir().join(format!("bodo_connect_resolve_{}.json", std::process::id()));
+    nm.settings_mut().cache_path = Some(path.clone());
+    nm.settings_mut().resolve_timeout = 500;
+
+    let start = Instant::now();
+    let resolved = nm.resolve_subdomains().await;
+    assert!(start.elapsed().as_millis() < 1500);
+    assert_eq!(resolved.get("192.0.2.1"), Some(&vec!["192.0.2.1".parse().unwrap()]));
+    assert_eq!(resolved.get("2001:db8::1"), Some(&vec!["2001:db8::1".parse().unwrap()]));
+    assert!(!resolved.contains_key("fixed.example.com"));
+    assert!(!resolved.contains_key("unresolvable.invalid"));
+
+    let subdomain = |ip: &str| nm.match_external_ip(ip.parse().unwrap(), &resolved).map(|s| s.subdomain.clone());
+    assert_eq!(subdomain("192.0.2.1").as_deref(), Some("192.0.2.1"));
+    assert_eq!(subdomain("198.51.100.1").as_deref(), Some("fixed.example.com"));
+    assert_eq!(subdomain("203.0.113.1"), None);
+    assert_eq!(nm.get_subnet_by_ip("2001:db8::1".parse().unwrap()).await.map(|s| s.subdomain.as_str()), Some("2001:db8::1"));
+
+    let cache = DetectionCache::load(&path);
+    assert_eq!(cache.get_resolved("192.0.2.1", 600), Some(&["192.0.2.1".parse().unwrap()][..]));
+    assert_eq!(cache.get_resolved("unresolvable.invalid", 600), None);
+    std::fs::remove_file(&path).unwrap();
+}